@@ -4,4 +4,4 @@ mod day9;
 mod day10;
 mod day11;
 mod day12;
-mod day13;
\ No newline at end of file
+pub mod day13;
\ No newline at end of file
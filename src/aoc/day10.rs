@@ -1,4 +1,6 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
 
 use itertools::Itertools;
 
@@ -28,42 +30,430 @@ static POINT_MAP2: phf::Map<char, u64> = phf::phf_map! {
     '>' => 4,
 };
 
-fn get_line_score(line: &str) -> Option<u32> {
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum LineStatus {
+    Ok,
+    Corrupted { expected: char, found: char, column: usize, opener_column: usize },
+    Incomplete { missing: String },
+    UnexpectedCloser { column: usize },
+}
+
+impl LineStatus {
+    fn render(&self, line_number: usize, line: &str) -> Option<String> {
+        let line_len = line.chars().count();
+        let (message, markers, label) = match self {
+            Self::Ok => return None,
+            Self::Corrupted { expected, found, column, opener_column } => (
+                "mismatched closing bracket".to_owned(),
+                vec![(*opener_column, '-'), (*column, '^')],
+                format!("expected `{}`, found `{}`", expected, found),
+            ),
+            Self::Incomplete { missing } => (
+                "incomplete line".to_owned(),
+                vec![(line_len, '^')],
+                format!("missing `{}`", missing),
+            ),
+            Self::UnexpectedCloser { column } => (
+                format!("unexpected closing bracket `{}`", line.chars().nth(*column).unwrap_or(' ')),
+                vec![(*column, '^')],
+                "no matching opening bracket".to_owned(),
+            ),
+        };
+
+        let primary_column = markers.last().unwrap().0;
+        let gutter = " ".repeat(line_number.to_string().len());
+        let mut marker_line = String::new();
+        for (column, marker) in markers {
+            marker_line.push_str(&" ".repeat(column - marker_line.chars().count()));
+            marker_line.push(marker);
+        }
+
+        Some(format!(
+            "error: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {} {}\n",
+            message,
+            gutter, line_number, primary_column + 1,
+            gutter,
+            line_number, line,
+            gutter, marker_line, label,
+        ))
+    }
+}
+
+fn check_line(line: &str) -> LineStatus {
     let mut stack = VecDeque::new();
-    for ch in line.trim().chars() {
+    for (column, ch) in line.chars().enumerate() {
         if OPENING_BRACKETS.contains(&ch) {
-            stack.push_back(ch)
+            stack.push_back((column, ch))
         } else if CLOSING_BRACKETS.contains(&ch) {
-            let open_ch = stack.pop_back().unwrap();
+            let (opener_column, open_ch) = match stack.pop_back() {
+                Some(opener) => opener,
+                None => return LineStatus::UnexpectedCloser { column },
+            };
             if BRACKET_MAP[&open_ch] != ch {
-                return Some(POINT_MAP[&ch]);
+                return LineStatus::Corrupted {
+                    expected: BRACKET_MAP[&open_ch],
+                    found: ch,
+                    column,
+                    opener_column,
+                };
             }
         }
     }
-    None
+
+    if stack.is_empty() {
+        LineStatus::Ok
+    } else {
+        LineStatus::Incomplete {
+            missing: stack.into_iter()
+                .rev()
+                .map(|(_, ch)| BRACKET_MAP[&ch])
+                .collect(),
+        }
+    }
 }
 
-fn get_line_score2(line: &str) -> Option<u64> {
+fn check_lines(input: &str) -> impl Iterator<Item = (usize, LineStatus)> + '_ {
+    input.lines()
+        .map(|line| line.trim_end())
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| (i + 1, check_line(line)))
+}
+
+fn render_diagnostics(input: &str) -> String {
+    let lines = input.lines().collect_vec();
+    check_lines(input)
+        .filter_map(|(line_number, status)| status.render(line_number, lines[line_number - 1].trim_end()))
+        .join("\n")
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Edit {
+    Insert { column: usize, text: String },
+    Delete { column: usize },
+    Replace { column: usize, with: char },
+}
+
+impl Edit {
+    fn column(&self) -> usize {
+        match *self {
+            Self::Insert { column, .. } | Self::Delete { column } | Self::Replace { column, .. } => column,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Recovery<'a> {
+    line: &'a str,
+    errors: Vec<LineStatus>,
+}
+
+impl Recovery<'_> {
+    fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    // Computed on demand: finding the minimal repair is cubic in the line length, while the
+    // errors come from a single pass.
+    fn repair(&self) -> Vec<Edit> {
+        minimal_edits(self.line)
+    }
+
+    fn repaired(&self) -> String {
+        apply_edits(self.line, &self.repair())
+    }
+}
+
+// Edits must be in column order, so a single pass over the line applies all of them.
+fn apply_edits(line: &str, edits: &[Edit]) -> String {
+    let mut edits = edits.iter().peekable();
+    let mut repaired = String::new();
+    for (column, ch) in line.chars().enumerate() {
+        let mut keep = true;
+        while let Some(edit) = edits.next_if(|edit| edit.column() == column) {
+            match edit {
+                Edit::Insert { text, .. } => repaired.push_str(text),
+                Edit::Delete { .. } => keep = false,
+                Edit::Replace { with, .. } => {
+                    repaired.push(*with);
+                    keep = false;
+                }
+            }
+        }
+        if keep {
+            repaired.push(ch);
+        }
+    }
+    for edit in edits {
+        if let Edit::Insert { text, .. } = edit {
+            repaired.push_str(text);
+        }
+    }
+    repaired
+}
+
+// Reports every error by resynchronising after each one: a missing closer is assumed when the
+// found closer matches the opener below the top of the stack, a closer on an empty stack is
+// skipped and any other mismatch is treated as the expected closer.
+fn check_line_recovering(line: &str) -> Recovery<'_> {
     let mut stack = VecDeque::new();
-    for ch in line.trim().chars() {
+    let mut errors = Vec::new();
+    for (column, ch) in line.chars().enumerate() {
         if OPENING_BRACKETS.contains(&ch) {
-            stack.push_back(ch)
+            stack.push_back((column, ch))
         } else if CLOSING_BRACKETS.contains(&ch) {
-            let open_ch = stack.pop_back().unwrap();
-            if BRACKET_MAP[&open_ch] != ch {
-                return None;
+            let (opener_column, open_ch) = match stack.pop_back() {
+                Some(opener) => opener,
+                None => {
+                    errors.push(LineStatus::UnexpectedCloser { column });
+                    continue;
+                }
+            };
+            if BRACKET_MAP[&open_ch] == ch {
+                continue;
+            }
+
+            errors.push(LineStatus::Corrupted {
+                expected: BRACKET_MAP[&open_ch],
+                found: ch,
+                column,
+                opener_column,
+            });
+            if matches!(stack.back(), Some(&(_, outer_ch)) if BRACKET_MAP[&outer_ch] == ch) {
+                stack.pop_back();
             }
         }
     }
 
-    Some(
-        stack
-            .into_iter()
+    if !stack.is_empty() {
+        let missing: String = stack.into_iter()
             .rev()
-            .map(|ch| BRACKET_MAP[&ch])
-            .map(|ch| POINT_MAP2[&ch])
-            .fold(0u64, |acc, x| acc * 5 + x),
-    )
+            .map(|(_, ch)| BRACKET_MAP[&ch])
+            .collect();
+        errors.push(LineStatus::Incomplete { missing });
+    }
+
+    Recovery { line, errors }
+}
+
+#[derive(Copy, Clone)]
+enum Bracket {
+    Open(char),
+    Close(char),
+}
+
+impl Bracket {
+    fn from_char(ch: char) -> Option<Bracket> {
+        match ch {
+            '(' | '[' | '{' | '<' => Some(Bracket::Open(ch)),
+            ')' | ']' | '}' | '>' => Some(Bracket::Close(ch)),
+            _ => None,
+        }
+    }
+}
+
+fn opener_for(closer: char) -> char {
+    match closer {
+        ')' => '(',
+        ']' => '[',
+        '}' => '{',
+        _ => '<',
+    }
+}
+
+// Cost of making the two brackets a matching pair, if a single replacement is enough.
+fn pair_cost(open: Bracket, close: Bracket) -> Option<usize> {
+    match (open, close) {
+        (Bracket::Open(open), Bracket::Close(close)) if BRACKET_MAP[&open] == close => Some(0),
+        (Bracket::Open(_), _) | (Bracket::Close(_), Bracket::Close(_)) => Some(1),
+        (Bracket::Close(_), Bracket::Open(_)) => None,
+    }
+}
+
+// The replacement `pair_cost` charges for, given the columns of the two brackets.
+fn pair_edit(open: (usize, Bracket), close: (usize, Bracket)) -> Option<Edit> {
+    match (open, close) {
+        ((_, Bracket::Open(open)), (column, close)) => {
+            let expected = BRACKET_MAP[&open];
+            match close {
+                Bracket::Close(close) if close == expected => None,
+                _ => Some(Edit::Replace { column, with: expected }),
+            }
+        }
+        ((column, _), (_, Bracket::Close(close))) => Some(Edit::Replace { column, with: opener_for(close) }),
+        _ => unreachable!("a closer cannot pair with a later opener"),
+    }
+}
+
+// The fewest single-character edits that balance the line. `cost[i][j]` is the cheapest repair of
+// `chars[i..j]`: `chars[i]` either stays unpaired, costing an inserted closer at `j` or a
+// deletion, or pairs with some bracket `chars[k]` and the two sides are repaired independently.
+fn minimal_edits(line: &str) -> Vec<Edit> {
+    let brackets = line.chars().map(Bracket::from_char).collect_vec();
+    let n = brackets.len();
+    let mut cost = vec![vec![0usize; n + 1]; n + 1];
+    for len in 1..=n {
+        for i in 0..=n - len {
+            let j = i + len;
+            let Some(open) = brackets[i] else {
+                cost[i][j] = cost[i + 1][j];
+                continue;
+            };
+            let mut best = 1 + cost[i + 1][j];
+            for k in i + 1..j {
+                if let Some(pair) = brackets[k].and_then(|close| pair_cost(open, close)) {
+                    best = best.min(pair + cost[i + 1][k] + cost[k + 1][j]);
+                }
+            }
+            cost[i][j] = best;
+        }
+    }
+
+    // Intervals are taken outermost first, so a closer inserted at a column shared with an
+    // enclosing interval goes in front of the enclosing one's.
+    let mut edits = Vec::new();
+    let mut inserts: BTreeMap<usize, String> = BTreeMap::new();
+    let mut intervals = vec![(0, n)];
+    while let Some((i, j)) = intervals.pop() {
+        if i == j {
+            continue;
+        }
+        let Some(open) = brackets[i] else {
+            intervals.push((i + 1, j));
+            continue;
+        };
+        let paired = (i + 1..j).find_map(|k| {
+            let close = brackets[k]?;
+            let pair = pair_cost(open, close)?;
+            (pair + cost[i + 1][k] + cost[k + 1][j] == cost[i][j]).then_some((k, close))
+        });
+        if let Some((k, close)) = paired {
+            edits.extend(pair_edit((i, open), (k, close)));
+            intervals.push((k + 1, j));
+            intervals.push((i + 1, k));
+        } else {
+            match open {
+                Bracket::Open(open) => inserts.entry(j).or_default().insert(0, BRACKET_MAP[&open]),
+                Bracket::Close(_) => edits.push(Edit::Delete { column: i }),
+            }
+            intervals.push((i + 1, j));
+        }
+    }
+
+    edits.extend(inserts.into_iter().map(|(column, text)| Edit::Insert { column, text }));
+    edits.sort_by_key(|edit| (edit.column(), !matches!(edit, Edit::Insert { .. })));
+    edits
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Chunk {
+    kind: char,
+    children: Vec<Chunk>,
+    span: Range<usize>,
+}
+
+impl Chunk {
+    fn depth(&self) -> usize {
+        1 + self.children.iter().map(|child| child.depth()).max().unwrap_or(0)
+    }
+
+    fn count_kinds(&self, counts: &mut BTreeMap<char, usize>) {
+        *counts.entry(self.kind).or_insert(0) += 1;
+        self.children.iter().for_each(|child| child.count_kinds(counts));
+    }
+
+    fn fmt_indented(&self, f: &mut Formatter<'_>, indent: usize) -> std::fmt::Result {
+        writeln!(f, "{}{}{} {}..{}", "  ".repeat(indent), self.kind, BRACKET_MAP[&self.kind], self.span.start, self.span.end)?;
+        self.children.iter().try_for_each(|child| child.fmt_indented(f, indent + 1))
+    }
+}
+
+impl Display for Chunk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct ChunkStats {
+    max_depth: usize,
+    counts: BTreeMap<char, usize>,
+}
+
+fn chunk_stats(chunks: &[Chunk]) -> ChunkStats {
+    let mut counts = BTreeMap::new();
+    chunks.iter().for_each(|chunk| chunk.count_kinds(&mut counts));
+    ChunkStats {
+        max_depth: chunks.iter().map(|chunk| chunk.depth()).max().unwrap_or(0),
+        counts,
+    }
+}
+
+fn parse_chunks(line: &str) -> Result<Vec<Chunk>, LineStatus> {
+    match check_line(line) {
+        LineStatus::Ok => {}
+        status => return Err(status),
+    }
+
+    let mut stack: VecDeque<(usize, char, Vec<Chunk>)> = VecDeque::new();
+    let mut top_level = Vec::new();
+    for (column, ch) in line.chars().enumerate() {
+        if OPENING_BRACKETS.contains(&ch) {
+            stack.push_back((column, ch, Vec::new()))
+        } else if CLOSING_BRACKETS.contains(&ch) {
+            let (start, kind, children) = stack.pop_back().unwrap();
+            let chunk = Chunk { kind, children, span: start..column + 1 };
+            match stack.back_mut() {
+                Some((_, _, siblings)) => siblings.push(chunk),
+                None => top_level.push(chunk),
+            }
+        }
+    }
+    Ok(top_level)
+}
+
+// Spans refer to columns of the repaired line, which is returned alongside the chunks.
+fn parse_chunks_repaired(line: &str) -> (String, Vec<Chunk>) {
+    let repaired = apply_edits(line, &minimal_edits(line));
+    let chunks = parse_chunks(&repaired).unwrap();
+    (repaired, chunks)
+}
+
+fn get_line_score(line: &str) -> Option<u32> {
+    let line = line.trim();
+    match check_line(line) {
+        LineStatus::Corrupted { found, .. } => Some(POINT_MAP[&found]),
+        LineStatus::UnexpectedCloser { column } => line.chars().nth(column).map(|ch| POINT_MAP[&ch]),
+        _ => None,
+    }
+}
+
+fn complete_line(line: &str) -> Option<String> {
+    match check_line(line) {
+        LineStatus::Incomplete { missing } => Some(missing),
+        _ => None,
+    }
+}
+
+fn score_completion<F>(completion: &str, base: u64, value: F) -> u64
+    where F: Fn(char) -> u64 {
+    completion.chars()
+        .map(value)
+        .fold(0u64, |acc, x| acc * base + x)
+}
+
+fn get_line_score2(line: &str) -> Option<u64> {
+    complete_line(line.trim())
+        .map(|completion| score_completion(&completion, 5, |ch| POINT_MAP2[&ch]))
+}
+
+// For an even number of scores the lower of the two middle ones is taken.
+fn middle_score(scores: &[u64]) -> Option<u64> {
+    if scores.is_empty() {
+        return None;
+    }
+    let sorted = scores.iter().sorted().collect_vec();
+    Some(*sorted[(sorted.len() - 1) / 2])
 }
 
 fn process1(file_name: &str) -> u32 {
@@ -73,14 +463,13 @@ fn process1(file_name: &str) -> u32 {
         .sum()
 }
 
-fn process2(file_name: &str) -> u64 {
+fn process2(file_name: &str) -> Option<u64> {
     let scores: Vec<u64> = common::get_lines_iterator(file_name)
         .map(|line| line.unwrap())
         .filter_map(|line| get_line_score2(line.trim()))
-        .sorted()
         .collect();
 
-    scores[scores.len()/2]
+    middle_score(&scores)
 }
 
 #[cfg(test)]
@@ -104,12 +493,145 @@ mod tests {
     #[test]
     fn test2() {
         let result = super::process2(TEST_FILE);
-        assert_eq!(result, 288957);
+        assert_eq!(result, Some(288957));
     }
 
     #[test]
     fn run2() {
         let result = super::process2(INPUT_FILE);
-        println!("{}", result);
+        println!("{:?}", result);
+    }
+
+    #[test]
+    fn check_line() {
+        use super::LineStatus;
+
+        assert_eq!(super::check_line("[<>({}){}[([])<>]]"), LineStatus::Ok);
+        assert_eq!(
+            super::check_line("{([(<{}[<>[]}>{[]{[(<()>"),
+            LineStatus::Corrupted { expected: ']', found: '}', column: 12, opener_column: 7 }
+        );
+        assert_eq!(
+            super::check_line("[({(<(())[]>[[{[]{<()<>>"),
+            LineStatus::Incomplete { missing: "}}]])})]".to_owned() }
+        );
+        assert_eq!(super::check_line("()])"), LineStatus::UnexpectedCloser { column: 2 });
+        assert_eq!(super::get_line_score("()])"), Some(57));
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(super::complete_line("<{([{{}}[<[[[<>{}]]]>[]]"), Some("])}>".to_owned()));
+        assert_eq!(super::complete_line("[<>({}){}[([])<>]]"), None);
+        assert_eq!(super::complete_line("{([(<{}[<>[]}>{[]{[(<()>"), None);
+        assert_eq!(super::score_completion("])}>", 5, |ch| super::POINT_MAP2[&ch]), 294);
+        assert_eq!(super::score_completion("])}>", 10, |_| 1), 1111);
+    }
+
+    #[test]
+    fn middle_score() {
+        assert_eq!(super::middle_score(&[]), None);
+        assert_eq!(super::middle_score(&[5, 1, 3]), Some(3));
+        assert_eq!(super::middle_score(&[4, 1, 3, 2]), Some(2));
+    }
+
+    #[test]
+    fn recovery() {
+        use super::{Edit, LineStatus};
+
+        let line = "{(]>}<)";
+        let recovery = super::check_line_recovering(line);
+        assert_eq!(recovery.errors, vec![
+            LineStatus::Corrupted { expected: ')', found: ']', column: 2, opener_column: 1 },
+            LineStatus::Corrupted { expected: '}', found: '>', column: 3, opener_column: 0 },
+            LineStatus::UnexpectedCloser { column: 4 },
+            LineStatus::Corrupted { expected: '>', found: ')', column: 6, opener_column: 5 },
+        ]);
+        // Three edits are enough even though the scan reports four errors.
+        assert_eq!(recovery.repair(), vec![
+            Edit::Replace { column: 2, with: ')' },
+            Edit::Delete { column: 3 },
+            Edit::Replace { column: 6, with: '>' },
+        ]);
+        assert_eq!(recovery.repaired(), "{()}<>");
+        assert_eq!(super::minimal_edits("(((<"), vec![
+            Edit::Replace { column: 1, with: ')' },
+            Edit::Replace { column: 3, with: ')' },
+        ]);
+        assert_eq!(super::minimal_edits(")(").len(), 2);
+        assert_eq!(super::minimal_edits("[}").len(), 1);
+
+        let recovery = super::check_line_recovering("[(<>]{");
+        assert_eq!(recovery.repair(), vec![
+            Edit::Insert { column: 4, text: ")".to_owned() },
+            Edit::Insert { column: 6, text: "}".to_owned() },
+        ]);
+        assert_eq!(recovery.repaired(), "[(<>)]{}");
+
+        for line in crate::common::read_to_string(TEST_FILE).lines() {
+            let recovery = super::check_line_recovering(line);
+            assert!(!recovery.is_ok());
+            assert_eq!(super::check_line(&recovery.repaired()), LineStatus::Ok);
+        }
+        assert!(super::check_line_recovering("[<>({}){}[([])<>]]").is_ok());
+
+        // Reporting errors stays a single pass even on lines far too long to repair minimally.
+        let long = "{(]>}<)".repeat(50_000);
+        assert_eq!(super::check_line_recovering(&long).errors.len(), 4 * 50_000);
+        let line = "{(]>}<)".repeat(36);
+        let edits = super::minimal_edits(&line);
+        assert!(edits.len() <= 3 * 36);
+        assert_eq!(super::check_line(&super::apply_edits(&line, &edits)), LineStatus::Ok);
+    }
+
+    #[test]
+    fn chunks() {
+        let chunks = super::parse_chunks("[<>({}){}]()").unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].span, 0..10);
+        assert_eq!(chunks[0].children.iter().map(|chunk| chunk.kind).collect::<String>(), "<({");
+        assert_eq!(chunks[0].to_string(), "\
+[] 0..10
+  <> 1..3
+  () 3..7
+    {} 4..6
+  {} 7..9
+");
+
+        let stats = super::chunk_stats(&chunks);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.counts, common_macros::b_tree_map! { '(' => 2, '<' => 1, '[' => 1, '{' => 2 });
+
+        assert_eq!(
+            super::parse_chunks("(]"),
+            Err(super::LineStatus::Corrupted { expected: ')', found: ']', column: 1, opener_column: 0 })
+        );
+        let (repaired, chunks) = super::parse_chunks_repaired("[(<>]{");
+        assert_eq!(repaired, "[(<>)]{}");
+        assert_eq!(super::chunk_stats(&chunks).max_depth, 3);
+    }
+
+    #[test]
+    fn render() {
+        let input = crate::common::read_to_string(TEST_FILE);
+        let diagnostics = super::render_diagnostics(&input);
+        let expected = "\
+error: mismatched closing bracket
+ --> 3:13
+  |
+3 | {([(<{}[<>[]}>{[]{[(<()>
+  |        -    ^ expected `]`, found `}`
+";
+        assert!(diagnostics.contains(expected), "{}", diagnostics);
+
+        let status = super::check_line(")");
+        let expected = "\
+error: unexpected closing bracket `)`
+  --> 12:1
+   |
+12 | )
+   | ^ no matching opening bracket
+";
+        assert_eq!(status.render(12, ")").unwrap(), expected);
     }
 }
@@ -1,132 +1,341 @@
-use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::automaton::{Automaton, Boundary, Cycle, Grid, Neighbours, Rule};
+
+const MAX_CYCLE_SEARCH: usize = 1_000_000;
 
-#[derive(PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Hash, Clone)]
 enum Octopus {
-    Ready(u8),
+    Ready(u32),
     Flashed,
+    Resting(usize),
 }
 
 impl Octopus {
-    fn is_flashing(&self) -> bool {
+    fn is_flashing(&self, threshold: u32) -> bool {
         match *self {
-            Self::Ready(v) if v > 9 => { true }
+            Self::Ready(v) if v > threshold => { true }
             _ => false,
         }
     }
 
-    fn inc(&mut self) {
+    fn inc(&mut self, amount: u32) {
         *self = match self.clone() {
-            Self::Ready(v) => (Self::Ready(v + 1)),
-            oct => oct.clone(),
+            Self::Ready(v) => Self::Ready(v.saturating_add(amount)),
+            oct => oct,
         }
     }
 
-    fn flash(&mut self) {
+    fn flash(&mut self, threshold: u32) {
         *self = match self.clone() {
-            Self::Ready(v) if v > 9 => Self::Flashed,
+            Self::Ready(v) if v > threshold => Self::Flashed,
             oct => oct,
         }
     }
 
-    fn clean_flashed(&mut self) {
+    fn clean_flashed(&mut self, config: &FlashConfig) {
         *self = match self.clone() {
-            Self::Flashed => Self::Ready(0),
+            Self::Flashed if config.refractory > 0 => Self::Resting(config.refractory),
+            Self::Flashed => Self::Ready(config.reset),
+            Self::Resting(n) if n > 1 => Self::Resting(n - 1),
+            Self::Resting(_) => Self::Ready(config.reset),
             oct => oct,
         }
     }
+
+    fn to_char(&self) -> char {
+        match *self {
+            Self::Ready(v) if v <= 9 => (b'0' + v as u8) as char,
+            Self::Resting(_) => '-',
+            _ => '*',
+        }
+    }
+
+    fn to_rgb(&self, flashed: bool) -> [u8; 3] {
+        match *self {
+            Self::Resting(_) if !flashed => [40, 40, 40],
+            Self::Ready(v) if !flashed => {
+                let v = v.min(9) as u8 * 20;
+                [0, v / 2, v + 40]
+            }
+            _ => [255, 255, 255],
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
-struct Point {
-    x: i32,
-    y: i32,
+// An octopus flashes once its energy exceeds `threshold`, adds `increment` to each neighbour
+// and restarts from `reset`. With a non-zero `refractory` it then ignores all energy for that
+// many further steps.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+struct FlashConfig {
+    threshold: u32,
+    increment: u32,
+    reset: u32,
+    refractory: usize,
 }
 
-impl Point {
-    fn new(x: i32, y: i32) -> Point {
-        Point { x, y }
+impl Default for FlashConfig {
+    fn default() -> Self {
+        FlashConfig { threshold: 9, increment: 1, reset: 0, refractory: 0 }
+    }
+}
+
+struct Flash {
+    config: FlashConfig,
+}
+
+impl Rule for Flash {
+    type Cell = Octopus;
+
+    fn update(&self, octopus: &Octopus, _neighbours: Neighbours<'_, Octopus>) -> Octopus {
+        let mut octopus = octopus.clone();
+        octopus.inc(1);
+        octopus
     }
 
-    fn neighbors(&self) -> Vec<Point> {
-        vec![
-            Point::new(self.x + 1, self.y),
-            Point::new(self.x + 1, self.y + 1),
-            Point::new(self.x, self.y + 1),
-            Point::new(self.x - 1, self.y + 1),
-            Point::new(self.x - 1, self.y),
-            Point::new(self.x - 1, self.y - 1),
-            Point::new(self.x, self.y - 1),
-            Point::new(self.x + 1, self.y - 1),
-        ]
+    fn triggers(&self, octopus: &Octopus) -> bool {
+        octopus.is_flashing(self.config.threshold)
     }
+
+    fn fire(&self, octopus: &mut Octopus) {
+        octopus.flash(self.config.threshold)
+    }
+
+    fn propagate(&self, neighbour: &mut Octopus) {
+        neighbour.inc(self.config.increment)
+    }
+
+    fn settle(&self, octopus: &mut Octopus) -> bool {
+        let flashed = octopus == &Octopus::Flashed;
+        octopus.clean_flashed(&self.config);
+        flashed
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SnapshotCell {
+    Energy(u32),
+    Resting { resting: usize },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    step: usize,
+    boundary: Boundary,
+    config: FlashConfig,
+    grid: Vec<Vec<SnapshotCell>>,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+struct StepLog {
+    step: usize,
+    waves: Vec<Vec<(usize, usize)>>,
 }
 
 struct State {
-    octopuses: BTreeMap<Point, Octopus>,
+    automaton: Automaton<Flash>,
+    event_log: Option<Vec<StepLog>>,
 }
 
 impl State {
     pub fn from_str(s: &str) -> State {
-        let mut octopuses = BTreeMap::new();
-        s.split_whitespace()
+        State::with_boundary(s, Boundary::Open)
+    }
+
+    pub fn with_boundary(s: &str, boundary: Boundary) -> State {
+        State::new(s, boundary, FlashConfig::default())
+    }
+
+    pub fn new(s: &str, boundary: Boundary, config: FlashConfig) -> State {
+        let rows = s.split_whitespace()
             .map(|s| s.trim())
-            .enumerate()
-            .map(|(y, s)| {
-                s.chars().enumerate()
-                    .map(|(x, ch)| (x, y, ch.to_string().parse::<u8>().unwrap()))
+            .map(|s| {
+                s.chars()
+                    .map(|ch| Octopus::Ready(ch.to_string().parse::<u32>().unwrap()))
                     .collect::<Vec<_>>()
             })
-            .flatten()
-            .map(|(x, y, v)| (Point::new(x as i32, y as i32), v))
-            .for_each(|(p, v)| {
-                octopuses.insert(p, Octopus::Ready(v));
-            });
+            .collect_vec();
 
-        State { octopuses }
+        let grid = Grid::from_rows(rows).with_boundary(boundary);
+        State { automaton: Automaton::new(Flash { config }, grid), event_log: None }
+    }
+
+    pub fn from_json(json: &str) -> Result<State, String> {
+        let snapshot: Snapshot = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let width = snapshot.grid.first().map(|row| row.len()).unwrap_or(0);
+        if snapshot.grid.iter().any(|row| row.len() != width) {
+            return Err("grid rows differ in length".to_owned());
+        }
+        // Rows without cells would not survive `to_json`, which only sees the cells.
+        if width == 0 && !snapshot.grid.is_empty() {
+            return Err("grid rows are empty".to_owned());
+        }
+
+        let rows = snapshot.grid.into_iter()
+            .map(|row| row.into_iter()
+                .map(|cell| match cell {
+                    SnapshotCell::Energy(v) => Octopus::Ready(v),
+                    SnapshotCell::Resting { resting } => Octopus::Resting(resting),
+                })
+                .collect())
+            .collect();
+        let grid = Grid::from_rows(rows).with_boundary(snapshot.boundary);
+        let automaton = Automaton::new(Flash { config: snapshot.config }, grid)
+            .with_generation(snapshot.step);
+        Ok(State { automaton, event_log: None })
+    }
+
+    pub fn to_json(&self) -> String {
+        let grid = self.automaton.grid();
+        let snapshot = Snapshot {
+            step: self.automaton.generation(),
+            boundary: grid.boundary(),
+            config: self.automaton.rule().config.clone(),
+            grid: grid.cells().chunks(grid.width().max(1))
+                .map(|row| row.iter()
+                    .map(|octopus| match *octopus {
+                        Octopus::Ready(v) => SnapshotCell::Energy(v),
+                        Octopus::Resting(resting) => SnapshotCell::Resting { resting },
+                        Octopus::Flashed => unreachable!("flashed octopuses are reset at the end of each step"),
+                    })
+                    .collect())
+                .collect(),
+        };
+        serde_json::to_string(&snapshot).unwrap()
+    }
+
+    // The digit text accepted by `from_str`, or None if some octopus cannot be written as a
+    // single digit (resting, or charged beyond 9 under a custom threshold).
+    pub fn to_digits(&self) -> Option<String> {
+        let digits = self.render();
+        if digits.chars().all(|ch| ch.is_ascii_digit() || ch == '\n') { Some(digits) } else { None }
+    }
+
+    pub fn with_event_log(mut self) -> State {
+        self.event_log = Some(Vec::new());
+        self
+    }
+
+    pub fn event_log(&self) -> Option<&[StepLog]> {
+        self.event_log.as_deref()
     }
 
     pub fn step(&mut self) -> usize {
-        self.inc_all();
-        while let true = self.flash_all() {}
-        self.clean_flashed()
+        let flashes = self.automaton.step();
+        if let Some(log) = self.event_log.as_mut() {
+            let width = self.automaton.grid().width();
+            log.push(StepLog {
+                step: self.automaton.generation(),
+                waves: self.automaton.last_cascade().iter()
+                    .map(|wave| wave.iter().map(|&i| (i % width, i / width)).collect())
+                    .collect(),
+            });
+        }
+        flashes
     }
 
-    fn inc_all(&mut self) {
-        self.octopuses.values_mut()
-            .for_each(|octopus| octopus.inc())
+    pub fn len(&self) -> usize {
+        self.automaton.grid().len()
     }
 
-    fn flash_all(&mut self) -> bool {
-        let flashing_points = self.octopuses.iter_mut()
-            .filter_map(|(point, octopus)| {
-                if octopus.is_flashing() { Some(point) } else { None }
-            }).cloned()
-            .collect_vec();
+    pub fn render(&self) -> String {
+        self.automaton.render(Octopus::to_char)
+    }
+
+    // Cells that flashed during the last step.
+    fn flashed(&self) -> Vec<bool> {
+        let mut flashed = vec![false; self.automaton.grid().len()];
+        self.automaton.last_cascade().iter()
+            .flatten()
+            .for_each(|&i| flashed[i] = true);
+        flashed
+    }
+
+    pub fn render_ansi(&self) -> String {
+        let grid = self.automaton.grid();
+        grid.cells().iter()
+            .zip(self.flashed())
+            .map(|(octopus, flashed)| if flashed {
+                format!("\x1b[1;97m{}\x1b[0m", octopus.to_char())
+            } else {
+                format!("\x1b[34m{}\x1b[0m", octopus.to_char())
+            })
+            .chunks(grid.width().max(1))
+            .into_iter()
+            .map(|mut row| row.join(""))
+            .join("\n")
+    }
 
-        for point in &flashing_points {
-            self.octopuses.get_mut(point).unwrap().flash();
+    pub fn write_ppm<W: Write>(&self, out: &mut W, scale: usize) -> io::Result<()> {
+        let grid = self.automaton.grid();
+        write!(out, "P6\n{} {}\n255\n", grid.width() * scale, grid.height() * scale)?;
+        let flashed = self.flashed();
+        let width = grid.width().max(1);
+        for (row, flashed) in grid.cells().chunks(width).zip(flashed.chunks(width)) {
+            let line = row.iter()
+                .zip(flashed)
+                .flat_map(|(octopus, &flashed)| std::iter::repeat_n(octopus.to_rgb(flashed), scale))
+                .flatten()
+                .collect_vec();
+            for _ in 0..scale {
+                out.write_all(&line)?;
+            }
         }
+        Ok(())
+    }
 
-        let neighbors: Vec<_> = flashing_points.iter()
-            .flat_map(|point| point.neighbors().into_iter())
-            .filter(|point| self.octopuses.contains_key(point))
-            .collect();
-        neighbors.into_iter()
-            .for_each(|point| self.octopuses.get_mut(&point).unwrap().inc());
+    pub fn find_cycle(&self) -> Option<Cycle> {
+        self.automaton.find_cycle(MAX_CYCLE_SEARCH)
+    }
 
-        !flashing_points.is_empty()
+    pub fn flashes_after(&mut self, steps: usize) -> Option<usize> {
+        self.automaton.fast_forward(steps, MAX_CYCLE_SEARCH)
     }
+}
 
-    fn clean_flashed(&mut self) -> usize {
-        let mut count = 0;
-        self.octopuses.values_mut()
-            .for_each(|oct| {
-                oct.clean_flashed();
-                if oct == &Octopus::Ready(0) { count += 1 };
-            });
-        count
+struct AnimationConfig {
+    steps: usize,
+    delay: Duration,
+    frames_dir: Option<PathBuf>,
+    scale: usize,
+}
+
+impl Default for AnimationConfig {
+    fn default() -> Self {
+        AnimationConfig { steps: 100, delay: Duration::from_millis(100), frames_dir: None, scale: 8 }
+    }
+}
+
+// Redraws the grid in place after every step and, if a directory is given, writes each frame
+// (including the initial one) to it as frame_NNNN.ppm. Returns the total number of flashes.
+fn animate<W: Write>(state: &mut State, config: &AnimationConfig, out: &mut W) -> io::Result<usize> {
+    let mut total = 0;
+    write!(out, "\x1b[2J")?;
+    for frame in 0..=config.steps {
+        if frame > 0 {
+            thread::sleep(config.delay);
+            total += state.step();
+        }
+
+        write!(out, "\x1b[H\x1b[1mstep {:>5}  flashes {:>7}\x1b[0m\n{}\n", frame, total, state.render_ansi())?;
+        out.flush()?;
+
+        if let Some(dir) = &config.frames_dir {
+            let file = File::create(dir.join(format!("frame_{:04}.ppm", frame)))?;
+            let mut writer = BufWriter::new(file);
+            state.write_ppm(&mut writer, config.scale)?;
+            writer.flush()?;
+        }
     }
+    Ok(total)
 }
 
 fn process1(map: &str, iterations: usize) -> usize {
@@ -139,22 +348,30 @@ fn process1(map: &str, iterations: usize) -> usize {
         .sum()
 }
 
-fn process2(map: &str) -> usize {
+fn process2(map: &str) -> Result<usize, String> {
     let mut state = State::from_str(map);
-    let map_size = state.octopuses.len();
+    let map_size = state.len();
+    let cycle = state.find_cycle()
+        .ok_or(format!("no repeating state found within {} steps", MAX_CYCLE_SEARCH))?;
 
-    let mut counter = 0;
-    while state.step() != map_size {
-        counter += 1;
+    // Every reachable state has been seen once the first period is complete.
+    for counter in 1..=cycle.start + cycle.length {
+        if state.step() == map_size {
+            return Ok(counter);
+        }
     }
-
-    counter+1
+    Err(format!(
+        "octopuses never synchronise: states repeat every {} steps from step {}",
+        cycle.length, cycle.start,
+    ))
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::aoc::day11::{process1, process2};
+    use crate::aoc::day11::{animate, process1, process2, AnimationConfig, FlashConfig, State};
+    use crate::automaton::{Boundary, Cycle};
+    use crate::common::Lcg;
 
     const TEST_MAP: &'static str = r#"
 5483143223
@@ -186,13 +403,229 @@ mod tests {
     #[test]
     fn test2() {
         let result = process2(TEST_MAP);
-        assert_eq!(result, 195);
+        assert_eq!(result, Ok(195));
     }
 
     #[test]
     fn run2() {
         let input_map = crate::common::read_to_string(INPUT_FILE);
         let result = process2(&input_map);
-        println!("{}", result);
+        println!("{:?}", result);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cycle() {
+        let mut state = State::from_str(TEST_MAP);
+        assert_eq!(state.find_cycle(), Some(Cycle { start: 195, length: 10 }));
+        assert_eq!(state.flashes_after(100), Some(1656));
+
+        let mut state = State::from_str(TEST_MAP);
+        let after_sync = (1_000_000_000_000 - 195) / 10 * 100;
+        assert_eq!(state.flashes_after(1_000_000_000_000), Some(process1(TEST_MAP, 195) + after_sync));
+
+        let result = process2("14");
+        assert_eq!(result, Err("octopuses never synchronise: states repeat every 9 steps from step 0".to_owned()));
+    }
+
+    #[test]
+    fn animation() {
+        let dir = std::env::temp_dir().join(format!("day11_frames_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = AnimationConfig {
+            steps: 2,
+            delay: std::time::Duration::ZERO,
+            frames_dir: Some(dir.clone()),
+            scale: 2,
+        };
+
+        let mut state = State::from_str("11111 19991 19191 19991 11111");
+        let mut out = Vec::new();
+        assert_eq!(animate(&mut state, &config, &mut out).unwrap(), 9);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\x1b[H").count(), 3);
+        assert!(out.contains("step     1  flashes       9"));
+        assert!(out.contains("\x1b[34m3\x1b[0m\x1b[34m4\x1b[0m\x1b[34m5\x1b[0m"));
+        assert!(out.contains("\x1b[34m4\x1b[0m\x1b[1;97m0\x1b[0m"));
+
+        let frame = std::fs::read(dir.join("frame_0002.ppm")).unwrap();
+        assert!(frame.starts_with(b"P6\n10 10\n255\n"));
+        assert_eq!(frame.len(), "P6\n10 10\n255\n".len() + 10 * 10 * 3);
+        assert!(!dir.join("frame_0003.ppm").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ppm_highlights_last_cascade() {
+        let config = FlashConfig { reset: 2, ..FlashConfig::default() };
+        let mut state = State::new("000 090 000", Boundary::Open, config);
+        let header = "P6\n3 3\n255\n".len();
+        let pixel = |frame: &[u8], i: usize| frame[header + 3 * i..header + 3 * i + 3].to_vec();
+
+        let mut frame = Vec::new();
+        state.write_ppm(&mut frame, 1).unwrap();
+        assert_ne!(pixel(&frame, 0), vec![255, 255, 255]);
+
+        state.step();
+        let mut frame = Vec::new();
+        state.write_ppm(&mut frame, 1).unwrap();
+        assert_eq!(pixel(&frame, 4), vec![255, 255, 255]);
+        assert_ne!(pixel(&frame, 0), vec![255, 255, 255]);
+    }
+
+    #[test]
+    fn boundaries() {
+        let map = "9000 0000 0000 0000";
+
+        let mut state = State::with_boundary(map, Boundary::Open);
+        state.step();
+        assert_eq!(state.render(), "0211\n2211\n1111\n1111");
+
+        let mut state = State::with_boundary(map, Boundary::Toroidal);
+        state.step();
+        assert_eq!(state.render(), "0212\n2212\n1111\n2212");
+
+        let mut state = State::with_boundary(map, Boundary::Reflecting);
+        state.step();
+        assert_eq!(state.render(), "0311\n3211\n1111\n1111");
+    }
+
+    #[test]
+    fn flash_config() {
+        let config = FlashConfig { threshold: 5, increment: 3, reset: 2, refractory: 0 };
+        let mut state = State::new("50 00", Boundary::Open, config);
+        assert_eq!(state.step(), 1);
+        assert_eq!(state.render(), "24\n44");
+
+        let config = FlashConfig { refractory: 1, ..FlashConfig::default() };
+        let mut state = State::new("11111 19991 19191 19991 11111", Boundary::Open, config);
+        assert_eq!(state.step(), 9);
+        assert_eq!(state.render(), "34543\n4---4\n5---5\n4---4\n34543");
+        assert_eq!(state.step(), 0);
+        assert_eq!(state.render(), "45654\n50005\n60006\n50005\n45654");
+    }
+
+    #[test]
+    fn event_log() {
+        let mut state = State::from_str("11111 19991 19191 19991 11111").with_event_log();
+        state.step();
+        state.step();
+
+        let log = state.event_log().unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].step, 1);
+        assert_eq!(log[0].waves.len(), 2);
+        assert_eq!(log[0].waves[0].len(), 8);
+        assert_eq!(log[0].waves[1], vec![(2, 2)]);
+        assert!(log[1].waves.is_empty());
+    }
+
+    #[test]
+    fn example_steps() {
+        let mut state = State::from_str(TEST_MAP);
+        let expected = [
+            (1, "6594254334 3856965822 6375667284 7252447257 7468496589 5278635756 3287952832 7993992245 5957959665 6394862637"),
+            (2, "8807476555 5089087054 8597889608 8485769600 8700908800 6600088989 6800005943 0000007456 9000000876 8700006848"),
+            (10, "0481112976 0031112009 0041112504 0081111406 0099111306 0093511233 0442361130 5532252350 0532250600 0032240000"),
+            (100, "0397666866 0749766918 0053976933 0004297822 0004229892 0053222877 0532222966 9322228966 7922286866 6789998766"),
+        ];
+        for (step, grid) in expected {
+            state.automaton.run(step - state.automaton.generation());
+            assert_eq!(state.to_digits().unwrap(), grid.replace(' ', "\n"), "after step {}", step);
+        }
+    }
+
+    #[test]
+    fn empty_grid() {
+        let mut state = State::from_str("");
+        assert_eq!(state.step(), 0);
+        assert_eq!(state.render_ansi(), "");
+        let mut frame = Vec::new();
+        state.write_ppm(&mut frame, 2).unwrap();
+        assert_eq!(frame, b"P6\n0 0\n255\n");
+
+        let json = state.to_json();
+        let restored = State::from_json(&json).unwrap();
+        assert_eq!(restored.len(), 0);
+        assert_eq!(restored.to_json(), json);
+        assert!(State::from_json(&json.replace("\"grid\":[]", "\"grid\":[[],[]]")).is_err());
+    }
+
+    #[test]
+    fn snapshot() {
+        let config = FlashConfig { refractory: 2, ..FlashConfig::default() };
+        let mut state = State::new(TEST_MAP, Boundary::Toroidal, config);
+        state.automaton.run(50);
+        assert_eq!(state.to_digits(), None);
+
+        let json = state.to_json();
+        assert!(json.starts_with(r#"{"step":50,"boundary":"toroidal","config":{"threshold":9,"increment":1,"reset":0,"refractory":2},"grid":[["#));
+        let mut restored = State::from_json(&json).unwrap();
+        assert_eq!(restored.automaton.generation(), 50);
+        assert_eq!(restored.render(), state.render());
+
+        state.automaton.run(50);
+        restored.automaton.run(50);
+        assert_eq!(restored.to_json(), state.to_json());
+
+        let state = State::from_str("123 456");
+        assert_eq!(state.to_json(), r#"{"step":0,"boundary":"open","config":{"threshold":9,"increment":1,"reset":0,"refractory":0},"grid":[[1,2,3],[4,5,6]]}"#);
+        assert_eq!(State::from_json(&state.to_json()).unwrap().to_digits().unwrap(), "123\n456");
+        assert!(State::from_json(r#"{"step":0,"boundary":"open","config":{"threshold":9,"increment":1,"reset":0,"refractory":0},"grid":[[1,2],[3]]}"#).is_err());
+    }
+
+    #[test]
+    fn small_steps() {
+        let mut state = State::from_str("11111 19991 19191 19991 11111");
+        assert_eq!(state.step(), 9);
+        assert_eq!(state.render(), "34543\n40004\n50005\n40004\n34543");
+        assert_eq!(state.step(), 0);
+        assert_eq!(state.render(), "45654\n51115\n61116\n51115\n45654");
+    }
+
+    fn random_map(width: usize, height: usize) -> String {
+        let mut random = Lcg::new(0x2545f491);
+        (0..height)
+            .map(|_| (0..width)
+                .map(|_| {
+                    (b'0' + random.below(10) as u8) as char
+                })
+                .collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn worklist_matches_rescan() {
+        let map = random_map(40, 30);
+        let mut worklist = State::from_str(&map);
+        let mut rescan = State::from_str(&map);
+        for _ in 0..200 {
+            assert_eq!(worklist.step(), rescan.automaton.step_rescan());
+        }
+        assert_eq!(worklist.render(), rescan.render());
+    }
+
+    // Compares against `step_rescan`, which rescans the same dense grid. It is not a measurement
+    // of the original `BTreeMap` based `flash_all`, which no longer exists.
+    #[test]
+    #[ignore]
+    fn bench_cascade() {
+        use std::time::Instant;
+
+        let map = random_map(500, 500);
+        let steps = 200;
+
+        let mut state = State::from_str(&map);
+        let start = Instant::now();
+        let worklist_flashes: usize = (0..steps).map(|_| state.step()).sum();
+        let worklist_time = start.elapsed();
+
+        let mut state = State::from_str(&map);
+        let start = Instant::now();
+        let rescan_flashes: usize = (0..steps).map(|_| state.automaton.step_rescan()).sum();
+        let rescan_time = start.elapsed();
+
+        assert_eq!(worklist_flashes, rescan_flashes);
+        println!("worklist: {:?}, rescan: {:?} ({} steps, {} flashes)", worklist_time, rescan_time, steps, worklist_flashes);
+    }
+}
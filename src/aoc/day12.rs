@@ -1,8 +1,10 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use itertools::Itertools;
+use rayon::prelude::*;
 
-#[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord)]
+#[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Debug)]
 enum Node {
     Start,
     End,
@@ -26,153 +28,754 @@ impl Node {
         match s {
             "start" => Node::Start,
             "end" => Node::End,
-            s if s.chars().next().unwrap().is_uppercase() => Node::Big(s.to_owned()),
+            s if s.starts_with(char::is_uppercase) => Node::Big(s.to_owned()),
             s => Node::Small(s.to_owned()),
         }
     }
+
+    fn parse(s: &str, line: usize) -> Result<Self, GraphError> {
+        if s.is_empty() {
+            return Err(GraphError::EmptyName { line });
+        }
+        if s.contains(char::is_uppercase) && s.contains(char::is_lowercase) {
+            return Err(GraphError::MixedCaseName { line, name: s.to_owned() });
+        }
+        Ok(Node::from_str(s))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum GraphError {
+    MalformedEdge { line: usize, text: String },
+    InvalidWeight { line: usize, text: String },
+    EmptyName { line: usize },
+    MixedCaseName { line: usize, name: String },
+    SelfLoop { line: usize, node: Node },
+    DuplicateEdge { line: usize, first_line: usize, a: Node, b: Node },
+    MissingNode(Node),
+    InfinitePaths(Node, Node),
+}
+
+impl Display for GraphError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MalformedEdge { line, text } => write!(f, "line {}: expected an edge like `a-b` or `a-b:3`, found `{}`", line, text),
+            Self::InvalidWeight { line, text } => write!(f, "line {}: invalid edge weight `{}`", line, text),
+            Self::EmptyName { line } => write!(f, "line {}: empty cave name", line),
+            Self::MixedCaseName { line, name } => write!(f, "line {}: cave name `{}` mixes upper and lower case", line, name),
+            Self::SelfLoop { line, node } => write!(f, "line {}: cave {} is connected to itself", line, node),
+            Self::DuplicateEdge { line, first_line, a, b } => write!(f, "line {}: edge {}-{} was already given on line {}", line, a, b, first_line),
+            Self::MissingNode(node) => write!(f, "no edge touches {}", node),
+            Self::InfinitePaths(a, b) => write!(
+                f,
+                "edge {}-{} connects two big caves on the way from start to end, so there are infinitely many paths",
+                a, b,
+            ),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum GraphWarning {
+    Unreachable(Node),
+    DeadEnd(Node),
+}
+
+impl Display for GraphWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable(node) => write!(f, "cave {} cannot be reached from start", node),
+            Self::DeadEnd(node) => write!(f, "cave {} has no way to end", node),
+        }
+    }
+}
+
+fn edge_key(a: &Node, b: &Node) -> (Node, Node) {
+    if a <= b { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) }
+}
+
+// Quoted DOT identifier. Cave names are not restricted to identifier characters, so quotes and
+// backslashes are escaped.
+fn dot_id(node: &Node) -> String {
+    format!("\"{}\"", node.to_string().replace('\\', "\\\\").replace('"', "\\\""))
 }
 
 struct Graph {
     graph: HashMap<Node, HashSet<Node>>,
+    weights: HashMap<(Node, Node), u64>,
 }
 
 impl Graph {
-    fn from_str(s: &str) -> Self {
-        let graph: HashMap<Node, HashSet<Node>> = s.trim().lines()
-            .map(|line| line.trim())
-            .map(|line| line.split_once("-").unwrap())
-            .map(|(a, b)| (Node::from_str(a), Node::from_str(b)))
-            .map(|(a, b)| match (a, b) {
+    // Edges are `a-b`, optionally weighted as `a-b:w`; unweighted edges weigh 1. All malformed
+    // lines are reported, numbered from the first line of `s`.
+    fn from_str(s: &str) -> Result<Self, Vec<GraphError>> {
+        let mut graph: HashMap<Node, HashSet<Node>> = HashMap::new();
+        let mut weights = HashMap::new();
+        let mut edge_lines = HashMap::new();
+        let mut errors = Vec::new();
+
+        for (i, line) in s.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let (a, b, weight) = match Graph::parse_edge(line.trim(), i + 1) {
+                Ok(edge) => edge,
+                Err(error) => {
+                    errors.push(error);
+                    continue;
+                }
+            };
+
+            let key = edge_key(&a, &b);
+            if let Some(&first_line) = edge_lines.get(&key) {
+                errors.push(GraphError::DuplicateEdge { line: i + 1, first_line, a, b });
+                continue;
+            }
+            edge_lines.insert(key.clone(), i + 1);
+            weights.insert(key, weight);
+
+            let edges = match (a, b) {
                 (a @ Node::Start, b) => vec![(a, b)],
                 (a, b @ Node::End) => vec![(a, b)],
                 (a, b @ Node::Start) => vec![(b, a)],
                 (a @ Node::End, b) => vec![(b, a)],
                 (a, b) => vec![(b.clone(), a.clone()), (a, b)],
-            }).flatten()
-            .into_grouping_map()
-            .collect();
-        Graph { graph }
+            };
+            for (from, to) in edges {
+                graph.entry(from).or_default().insert(to);
+            }
+        }
+
+        for node in [Node::Start, Node::End] {
+            if !edge_lines.keys().any(|(a, b)| a == &node || b == &node) {
+                errors.push(GraphError::MissingNode(node));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let graph = Graph { graph, weights };
+        graph.check_finite().map_err(|error| vec![error])?;
+        Ok(graph)
     }
 
-    fn get_paths(&self) -> Vec<Vec<Node>> {
-        let mut to_visit_stack = VecDeque::new();
-        let mut current_stack = VecDeque::new();
-        let mut visited_small = HashSet::new();
+    fn parse_edge(line: &str, line_number: usize) -> Result<(Node, Node, u64), GraphError> {
+        let (edge, weight) = match line.split_once(':') {
+            Some((edge, weight)) => {
+                let weight = weight.trim().parse::<u64>()
+                    .map_err(|_| GraphError::InvalidWeight { line: line_number, text: weight.trim().to_owned() })?;
+                (edge, weight)
+            }
+            None => (line, 1),
+        };
+        let (a, b) = match edge.split('-').collect_vec()[..] {
+            [a, b] => (a.trim(), b.trim()),
+            _ => return Err(GraphError::MalformedEdge { line: line_number, text: line.to_owned() }),
+        };
 
-        let mut paths = Vec::new();
+        let a = Node::parse(a, line_number)?;
+        let b = Node::parse(b, line_number)?;
+        if a == b {
+            return Err(GraphError::SelfLoop { line: line_number, node: a });
+        }
+        Ok((a, b, weight))
+    }
 
-        let starting_nodes = self.graph[&Node::Start].iter().cloned().collect::<VecDeque<_>>();
-        to_visit_stack.push_back(starting_nodes);
-        while !to_visit_stack.is_empty() {
-            let current_node = to_visit_stack.back_mut().unwrap().pop_front();
-            if current_node.is_none() {
-                to_visit_stack.pop_back().unwrap();
-                let last = current_stack.pop_back().unwrap_or(Node::Start);
-                visited_small.remove(&last);
+    fn warnings(&self) -> Vec<GraphWarning> {
+        let from_start = self.reachable(Node::Start, |node| self.neighbors(node));
+        let to_end = self.reachable(Node::End, |node| self.predecessors(node));
+        self.weights.keys()
+            .flat_map(|(a, b)| [a, b])
+            .unique()
+            .sorted()
+            .filter_map(|node| if !from_start.contains(node) {
+                Some(GraphWarning::Unreachable(node.clone()))
+            } else if !to_end.contains(node) {
+                Some(GraphWarning::DeadEnd(node.clone()))
             } else {
-                let current_node = current_node.unwrap();
-                if let &Node::Small(_) = &current_node {
-                    visited_small.insert(current_node.clone());
-                }
+                None
+            })
+            .collect()
+    }
 
-                current_stack.push_back(current_node.clone());
-                to_visit_stack.push_back(self.get_neighbors(&current_node, &visited_small));
+    // Big caves have no visit limit, so two of them next to each other can be bounced between
+    // forever. Every cycle made of big caves only contains such an edge.
+    fn check_finite(&self) -> Result<(), GraphError> {
+        let from_start = self.reachable(Node::Start, |node| self.neighbors(node));
+        let to_end = self.reachable(Node::End, |node| self.predecessors(node));
 
-                if let &Node::End = &current_node {
-                    paths.push(current_stack.iter().cloned().collect::<Vec<_>>())
+        let relevant = |node: &Node| from_start.contains(node) && to_end.contains(node);
+        let infinite_edge = self.graph.iter()
+            .filter(|(a, _)| matches!(a, Node::Big(_)) && relevant(a))
+            .flat_map(|(a, neighbors)| neighbors.iter().map(move |b| (a, b)))
+            .filter(|(_, b)| matches!(b, Node::Big(_)) && relevant(b))
+            .map(|(a, b)| if a <= b { (a, b) } else { (b, a) })
+            .min();
+        match infinite_edge {
+            Some((a, b)) => Err(GraphError::InfinitePaths(a.clone(), b.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn reachable<F>(&self, from: Node, next: F) -> HashSet<Node>
+        where F: Fn(&Node) -> Vec<Node> {
+        let mut seen = HashSet::new();
+        let mut to_visit = vec![from];
+        while let Some(node) = to_visit.pop() {
+            if seen.insert(node.clone()) {
+                to_visit.extend(next(&node));
+            }
+        }
+        seen
+    }
+
+    fn neighbors(&self, node: &Node) -> Vec<Node> {
+        self.graph.get(node).map(|neighbors| neighbors.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    fn predecessors(&self, node: &Node) -> Vec<Node> {
+        self.graph.iter()
+            .filter(|(_, neighbors)| neighbors.contains(node))
+            .map(|(from, _)| from.clone())
+            .collect()
+    }
+
+    // Renders the caves as an undirected Graphviz graph; nodes and edges along `path`, if given,
+    // are drawn in red.
+    fn to_dot(&self, path: Option<&[Node]>) -> String {
+        let path = path.unwrap_or(&[]);
+        let path_edges: HashSet<(&Node, &Node)> = path.iter()
+            .tuple_windows()
+            .map(|(a, b)| if a <= b { (a, b) } else { (b, a) })
+            .collect();
+        let edges = self.graph.iter()
+            .flat_map(|(a, neighbors)| neighbors.iter().map(move |b| if a <= b { (a, b) } else { (b, a) }))
+            .unique()
+            .sorted()
+            .collect_vec();
+        let nodes = edges.iter()
+            .flat_map(|&(a, b)| [a, b])
+            .unique()
+            .sorted()
+            .collect_vec();
+
+        let mut dot = String::from("graph caves {\n");
+        for node in nodes {
+            let style = match node {
+                Node::Start => "shape=doublecircle, style=filled, fillcolor=palegreen",
+                Node::End => "shape=doublecircle, style=filled, fillcolor=lightcoral",
+                Node::Big(_) => "shape=box, style=filled, fillcolor=lightblue",
+                Node::Small(_) => "shape=ellipse",
+            };
+            let highlight = if path.contains(node) { ", color=red, penwidth=3" } else { "" };
+            dot.push_str(&format!("    {} [{}{}];\n", dot_id(node), style, highlight));
+        }
+        for (a, b) in edges {
+            let highlight = if path_edges.contains(&(a, b)) { " [color=red, penwidth=3]" } else { "" };
+            dot.push_str(&format!("    {} -- {}{};\n", dot_id(a), dot_id(b), highlight));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn index(&self) -> IndexedGraph {
+        let nodes = self.graph.iter()
+            .flat_map(|(node, neighbors)| std::iter::once(node).chain(neighbors))
+            .chain([&Node::Start, &Node::End])
+            .unique()
+            .sorted()
+            .cloned()
+            .collect_vec();
+        let ids: HashMap<&Node, usize> = nodes.iter().enumerate().map(|(id, node)| (node, id)).collect();
+
+        let adjacency: Vec<Vec<usize>> = nodes.iter()
+            .map(|node| self.graph.get(node)
+                .map(|neighbors| neighbors.iter().map(|n| ids[n]).sorted().collect())
+                .unwrap_or_default())
+            .collect();
+        let weights = adjacency.iter().enumerate()
+            .map(|(id, neighbors)| neighbors.iter()
+                .map(|&n| self.weights[&edge_key(&nodes[id], &nodes[n])])
+                .collect())
+            .collect();
+
+        IndexedGraph {
+            start: ids[&Node::Start],
+            end: ids[&Node::End],
+            ids: nodes.iter().cloned().enumerate().map(|(id, node)| (node, id)).collect(),
+            nodes,
+            adjacency,
+            weights,
+        }
+    }
+}
+
+// Small caves may be entered once and big caves any number of times, unless a node has its own
+// limit. Up to `extra_slots` small caves may additionally be entered up to `extra_limit` times,
+// forbidden nodes are never entered and a path only counts if it passes every waypoint.
+#[derive(Clone, Debug)]
+struct VisitPolicy {
+    limits: HashMap<Node, usize>,
+    extra_slots: usize,
+    extra_limit: usize,
+    forbidden: HashSet<Node>,
+    waypoints: HashSet<Node>,
+}
+
+impl VisitPolicy {
+    fn small_once() -> Self {
+        VisitPolicy {
+            limits: HashMap::new(),
+            extra_slots: 0,
+            extra_limit: 1,
+            forbidden: HashSet::new(),
+            waypoints: HashSet::new(),
+        }
+    }
+
+    fn one_small_twice() -> Self {
+        VisitPolicy::small_once().with_extra(1, 2)
+    }
+
+    fn with_extra(mut self, slots: usize, limit: usize) -> Self {
+        self.extra_slots = slots;
+        self.extra_limit = limit;
+        self
+    }
+
+    fn with_limit(mut self, node: Node, limit: usize) -> Self {
+        self.limits.insert(node, limit);
+        self
+    }
+
+    fn forbid(mut self, node: Node) -> Self {
+        self.forbidden.insert(node);
+        self
+    }
+
+    fn require(mut self, node: Node) -> Self {
+        self.waypoints.insert(node);
+        self
+    }
+
+    fn limit(&self, node: &Node) -> Option<usize> {
+        if self.forbidden.contains(node) {
+            return Some(0);
+        }
+        match (self.limits.get(node), node) {
+            (Some(&limit), _) => Some(limit),
+            (None, Node::Big(_)) => None,
+            (None, _) => Some(1),
+        }
+    }
+}
+
+// Caves interned to integer ids, so the traversal state is a plain vector of visit counts.
+struct IndexedGraph {
+    nodes: Vec<Node>,
+    ids: HashMap<Node, usize>,
+    adjacency: Vec<Vec<usize>>,
+    weights: Vec<Vec<u64>>,
+    start: usize,
+    end: usize,
+}
+
+impl IndexedGraph {
+    // Costs are summed in u128 so paths over large u64 weights never overflow.
+    fn path_cost(&self, path: &[Node]) -> u128 {
+        path.iter()
+            .map(|node| self.ids[node])
+            .tuple_windows()
+            .map(|(a, b)| {
+                let i = self.adjacency[a].iter().position(|&n| n == b).unwrap();
+                self.weights[a][i] as u128
+            })
+            .sum()
+    }
+
+    fn to_nodes(&self, path: &[usize]) -> Vec<Node> {
+        path.iter().map(|&id| self.nodes[id].clone()).collect()
+    }
+
+    // Plain Dijkstra: a shortest path never enters a cave twice, so no visit policy is needed.
+    fn shortest_path(&self) -> Option<(u128, Vec<Node>)> {
+        let mut distances = vec![u128::MAX; self.nodes.len()];
+        let mut previous = vec![None; self.nodes.len()];
+        let mut queue = BinaryHeap::new();
+        distances[self.start] = 0;
+        queue.push(Reverse((0, self.start)));
+
+        while let Some(Reverse((distance, node))) = queue.pop() {
+            if node == self.end {
+                let mut path = vec![node];
+                while let Some(prev) = previous[*path.last().unwrap()] {
+                    path.push(prev);
+                }
+                path.reverse();
+                return Some((distance, self.to_nodes(&path)));
+            }
+            if distance > distances[node] {
+                continue;
+            }
+            for (&next, &weight) in self.adjacency[node].iter().zip(&self.weights[node]) {
+                let next_distance = distance + weight as u128;
+                if next_distance < distances[next] {
+                    distances[next] = next_distance;
+                    previous[next] = Some(node);
+                    queue.push(Reverse((next_distance, next)));
                 }
             }
         }
-        paths
+        None
     }
 
-    fn get_paths2(&self) -> Vec<Vec<Node>> {
-        let mut to_visit_stack = VecDeque::new();
-        let mut current_stack = VecDeque::new();
-        let mut visited_small = HashSet::new();
-        let mut visited_twice: Option<Node> = None;
+    fn longest_path(&self, policy: &VisitPolicy) -> Option<(u128, Vec<Node>)> {
+        self.paths(policy)
+            .map(|path| (self.path_cost(&path), path))
+            .max_by_key(|(cost, _)| *cost)
+    }
 
-        let mut paths = Vec::new();
+    // Best-first search over partial paths, so complete paths come off the queue cheapest first.
+    fn k_shortest_paths(&self, policy: &VisitPolicy, k: usize) -> Vec<(u128, Vec<Node>)> {
+        let mut shortest = Vec::new();
+        if k == 0 {
+            return shortest;
+        }
+        let mut queue = BinaryHeap::new();
+        if self.walk(policy).enter(self.start) {
+            queue.push(Reverse((0, vec![self.start])));
+        }
 
-        let starting_nodes = self.graph[&Node::Start].iter().cloned().collect::<VecDeque<_>>();
-        to_visit_stack.push_back(starting_nodes);
-        while !to_visit_stack.is_empty() {
-            let current_node = to_visit_stack.back_mut().unwrap().pop_front();
-            if current_node.is_none() {
-                to_visit_stack.pop_back().unwrap();
-                let last = current_stack.pop_back().unwrap_or(Node::Start);
-                if visited_twice.as_ref() == Some(&last) {
-                    visited_twice = None;
-                } else {
-                    visited_small.remove(&last);
+        while let Some(Reverse((cost, path))) = queue.pop() {
+            let node = *path.last().unwrap();
+            if node == self.end {
+                shortest.push((cost, self.to_nodes(&path)));
+                if shortest.len() == k {
+                    break;
                 }
-            } else {
-                let current_node = current_node.unwrap();
-                if visited_small.contains(&current_node) {
-                    if visited_twice.is_none() {
-                        visited_twice = Some(current_node.clone());
-                    } else {
+                continue;
+            }
+
+            let mut walk = self.replay(policy, &path);
+            for (&next, &weight) in self.adjacency[node].iter().zip(&self.weights[node]) {
+                if !walk.enter(next) {
+                    continue;
+                }
+                if next != self.end || walk.all_waypoints_visited() {
+                    let mut next_path = path.clone();
+                    next_path.push(next);
+                    queue.push(Reverse((cost + weight as u128, next_path)));
+                }
+                walk.leave(next);
+            }
+        }
+        shortest
+    }
+
+    fn walk(&self, policy: &VisitPolicy) -> Walk {
+        let limits = self.nodes.iter().map(|node| policy.limit(node)).collect_vec();
+        let raised_limits = self.nodes.iter().zip(&limits)
+            .map(|(node, &limit)| match (node, limit) {
+                (Node::Small(_), Some(limit)) if limit > 0 => limit.max(policy.extra_limit),
+                (_, limit) => limit.unwrap_or(0),
+            })
+            .collect_vec();
+        let waypoints = self.nodes.iter().enumerate()
+            .filter(|(_, node)| policy.waypoints.contains(node))
+            .map(|(id, _)| id)
+            .collect_vec();
+        let tracked = self.nodes.iter().enumerate()
+            .map(|(id, _)| limits[id].is_some() || waypoints.contains(&id))
+            .collect_vec();
+
+        let mut shifts = Vec::with_capacity(self.nodes.len());
+        let mut bits = 0;
+        for id in 0..self.nodes.len() {
+            let max_visits = match limits[id] {
+                _ if !tracked[id] => 0,
+                Some(_) => raised_limits[id] as u64,
+                None => 1,
+            };
+            shifts.push(bits);
+            bits += u64::BITS - max_visits.leading_zeros();
+        }
+
+        Walk {
+            limits,
+            raised_limits,
+            extra_slots: policy.extra_slots,
+            tracked,
+            waypoints,
+            visits: vec![0; self.nodes.len()],
+            extra_used: 0,
+            shifts: if bits <= u128::BITS { Some(shifts) } else { None },
+            key: 0,
+        }
+    }
+
+    fn count_paths(&self, policy: &VisitPolicy) -> u64 {
+        let mut walk = self.walk(policy);
+        if !walk.enter(self.start) {
+            return 0;
+        }
+        let mut memo = HashMap::new();
+        self.count_from(self.start, &mut walk, &mut memo)
+    }
+
+    // Memoised on the visit counts the policy cares about.
+    fn count_from(&self, node: usize, walk: &mut Walk, memo: &mut HashMap<(usize, VisitKey), u64>) -> u64 {
+        if node == self.end {
+            return if walk.all_waypoints_visited() { 1 } else { 0 };
+        }
+        let key = (node, walk.memo_key());
+        if let Some(&count) = memo.get(&key) {
+            return count;
+        }
+
+        let mut count = 0;
+        for &next in &self.adjacency[node] {
+            if walk.enter(next) {
+                count += self.count_from(next, walk, memo);
+                walk.leave(next);
+            }
+        }
+        memo.insert(key, count);
+        count
+    }
+
+    fn paths(&self, policy: &VisitPolicy) -> Paths<'_> {
+        let mut walk = self.walk(policy);
+        let stack = if walk.enter(self.start) { vec![(self.start, 0)] } else { Vec::new() };
+        Paths { graph: self, stack, floor: 1, walk }
+    }
+
+    // Paths that continue `prefix`, which must be allowed by the policy and not end at `end`.
+    fn paths_from(&self, policy: &VisitPolicy, prefix: &[usize]) -> Paths<'_> {
+        let (&last, rest) = prefix.split_last().unwrap();
+        let stack = rest.iter()
+            .map(|&node| (node, self.adjacency[node].len()))
+            .chain([(last, 0)])
+            .collect();
+        Paths { graph: self, stack, floor: prefix.len(), walk: self.replay(policy, prefix) }
+    }
+
+    fn replay(&self, policy: &VisitPolicy, path: &[usize]) -> Walk {
+        let mut walk = self.walk(policy);
+        for &node in path {
+            walk.enter(node);
+        }
+        walk
+    }
+
+    // Expands partial paths breadth-first until there are at least `min_size` of them to hand
+    // out as independent subtrees. Paths completed along the way are returned separately.
+    fn frontier(&self, policy: &VisitPolicy, min_size: usize) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let mut open = Vec::new();
+        let mut complete = Vec::new();
+        if self.walk(policy).enter(self.start) {
+            open.push(vec![self.start]);
+        }
+
+        while !open.is_empty() && open.len() < min_size {
+            let mut next_open = Vec::new();
+            for prefix in open {
+                let mut walk = self.replay(policy, &prefix);
+                for &next in &self.adjacency[*prefix.last().unwrap()] {
+                    if !walk.enter(next) {
                         continue;
                     }
+                    let mut path = prefix.clone();
+                    path.push(next);
+                    if next != self.end {
+                        next_open.push(path);
+                    } else if walk.all_waypoints_visited() {
+                        complete.push(path);
+                    }
+                    walk.leave(next);
                 }
+            }
+            open = next_open;
+        }
+        (open, complete)
+    }
 
-                if let &Node::Small(_) = &current_node {
-                    visited_small.insert(current_node.clone());
-                }
+    // Memo keys hold the whole visit state, so each worker keeps one memo across its subtrees.
+    fn par_count_paths(&self, policy: &VisitPolicy) -> u64 {
+        let (open, complete) = self.frontier(policy, rayon::current_num_threads() * 4);
+        let counted: u64 = open.par_iter()
+            .map_init(HashMap::new, |memo, prefix| {
+                let mut walk = self.replay(policy, prefix);
+                self.count_from(*prefix.last().unwrap(), &mut walk, memo)
+            })
+            .sum();
+        complete.len() as u64 + counted
+    }
+
+    // Streams the paths, so callers that only fold over them never hold them all at once.
+    fn par_paths<'a>(&'a self, policy: &'a VisitPolicy) -> impl ParallelIterator<Item = Vec<Node>> + 'a {
+        let (open, complete) = self.frontier(policy, rayon::current_num_threads() * 4);
+        complete.into_par_iter()
+            .map(move |path| self.to_nodes(&path))
+            .chain(open.into_par_iter().flat_map_iter(move |prefix| self.paths_from(policy, &prefix)))
+    }
+}
 
-                current_stack.push_back(current_node.clone());
-                to_visit_stack.push_back(self.get_neighbors2(&current_node));
+#[derive(PartialEq, Eq, Hash)]
+enum VisitKey {
+    Packed(u128),
+    Unpacked(Vec<u32>),
+}
 
-                if let &Node::End = &current_node {
-                    paths.push(current_stack.iter().cloned().collect::<Vec<_>>())
-                }
+struct Walk {
+    limits: Vec<Option<usize>>,
+    raised_limits: Vec<usize>,
+    extra_slots: usize,
+    tracked: Vec<bool>,
+    waypoints: Vec<usize>,
+    visits: Vec<u32>,
+    extra_used: usize,
+    // The visit counts that matter to the policy, bit-packed per tracked node at `shifts[node]`.
+    // `None` when they need more than 128 bits.
+    shifts: Option<Vec<u32>>,
+    key: u128,
+}
+
+impl Walk {
+    // Limited nodes never exceed their raised limit, and waypoints only need to be told apart
+    // from unvisited.
+    fn packed_visits(&self, node: usize) -> u128 {
+        match self.limits[node] {
+            Some(_) => self.visits[node] as u128,
+            None => self.visits[node].min(1) as u128,
+        }
+    }
+
+    fn memo_key(&self) -> VisitKey {
+        match self.shifts {
+            Some(_) => VisitKey::Packed(self.key),
+            None => VisitKey::Unpacked(
+                (0..self.visits.len())
+                    .filter(|&node| self.tracked[node])
+                    .map(|node| self.packed_visits(node) as u32)
+                    .collect(),
+            ),
+        }
+    }
+
+    fn change_visits(&mut self, node: usize, entering: bool) {
+        let before = self.packed_visits(node);
+        if entering {
+            self.visits[node] += 1;
+        } else {
+            self.visits[node] -= 1;
+        }
+        if let Some(shifts) = &self.shifts {
+            let shift = shifts[node];
+            self.key = self.key - (before << shift) + (self.packed_visits(node) << shift);
+        }
+    }
+
+    fn can_enter(&self, node: usize) -> bool {
+        let visits = self.visits[node] as usize;
+        match self.limits[node] {
+            None => true,
+            Some(limit) if visits < limit => true,
+            Some(limit) => visits < self.raised_limits[node] && (visits > limit || self.extra_used < self.extra_slots),
+        }
+    }
+
+    fn enter(&mut self, node: usize) -> bool {
+        if !self.can_enter(node) {
+            return false;
+        }
+        if let Some(limit) = self.limits[node] {
+            if self.visits[node] as usize == limit {
+                self.extra_used += 1;
             }
         }
-        paths
+        if self.tracked[node] {
+            self.change_visits(node, true);
+        }
+        true
     }
 
-    fn get_neighbors(&self, node: &Node, visited_small: &HashSet<Node>) -> VecDeque<Node> {
-        self.graph.get(node).unwrap_or(&HashSet::new()).iter()
-            .filter(|node| !visited_small.contains(*node))
-            .cloned()
-            .collect()
+    fn leave(&mut self, node: usize) {
+        if self.tracked[node] {
+            self.change_visits(node, false);
+        }
+        if let Some(limit) = self.limits[node] {
+            if self.visits[node] as usize == limit {
+                self.extra_used -= 1;
+            }
+        }
     }
 
-    fn get_neighbors2(&self, node: &Node) -> VecDeque<Node> {
-        self.graph.get(node).unwrap_or(&HashSet::new()).iter()
-            .cloned()
-            .collect()
+    fn all_waypoints_visited(&self) -> bool {
+        self.waypoints.iter().all(|&id| self.visits[id] > 0)
     }
 }
 
-fn process1(input: &str) -> usize {
-    let graph = Graph::from_str(input);
-    graph.get_paths().iter()
-        .inspect(|path| {
-            for node in path.iter() {
-                print!("{},", node);
+struct Paths<'a> {
+    graph: &'a IndexedGraph,
+    stack: Vec<(usize, usize)>,
+    floor: usize,
+    walk: Walk,
+}
+
+impl Iterator for Paths<'_> {
+    type Item = Vec<Node>;
+
+    fn next(&mut self) -> Option<Vec<Node>> {
+        loop {
+            if self.stack.len() < self.floor {
+                return None;
+            }
+            let (node, next_neighbor) = self.stack.last_mut()?;
+            let node = *node;
+            let neighbors = &self.graph.adjacency[node];
+            if *next_neighbor == neighbors.len() {
+                self.stack.pop();
+                self.walk.leave(node);
+                continue;
+            }
+
+            let next = neighbors[*next_neighbor];
+            *next_neighbor += 1;
+            if !self.walk.enter(next) {
+                continue;
+            }
+            if next != self.graph.end {
+                self.stack.push((next, 0));
+                continue;
+            }
+
+            let complete = self.walk.all_waypoints_visited();
+            self.walk.leave(next);
+            if complete {
+                let path = self.stack.iter()
+                    .map(|&(node, _)| node)
+                    .chain([next])
+                    .map(|node| self.graph.nodes[node].clone())
+                    .collect();
+                return Some(path);
             }
-            println!();
-        })
-        .count()
+        }
+    }
+}
+
+fn process1(input: &str) -> Result<u64, Vec<GraphError>> {
+    let graph = Graph::from_str(input)?;
+    Ok(graph.index().count_paths(&VisitPolicy::small_once()))
 }
 
-fn process2(input: &str) -> usize {
-    let graph = Graph::from_str(input);
-    graph.get_paths2().iter()
-        .sorted()
-        // .inspect(|path| {
-        //     for node in path.iter() {
-        //         print!("{},", node);
-        //     }
-        //     println!();
-        // })
-        .count()
+fn process2(input: &str) -> Result<u64, Vec<GraphError>> {
+    let graph = Graph::from_str(input)?;
+    Ok(graph.index().count_paths(&VisitPolicy::one_small_twice()))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::aoc::day12::{process1, process2};
+    use itertools::Itertools;
+    use rayon::prelude::*;
+    use crate::aoc::day12::{process1, process2, Graph, GraphError, GraphWarning, Node, VisitPolicy};
     use crate::common::read_to_string;
 
     const TEST_GRAPH1: &'static str = r#"
@@ -221,50 +824,262 @@ start-RW"#;
     #[test]
     fn test1() {
         let result = process1(TEST_GRAPH1);
-        assert_eq!(result, 10);
+        assert_eq!(result, Ok(10));
     }
 
     #[test]
     fn test2() {
         let result = process1(TEST_GRAPH2);
-        assert_eq!(result, 19);
+        assert_eq!(result, Ok(19));
     }
 
     #[test]
     fn test3() {
         let result = process1(TEST_GRAPH3);
-        assert_eq!(result, 226);
+        assert_eq!(result, Ok(226));
     }
 
     #[test]
     fn run() {
         let input = read_to_string(INPUT_FILE);
         let result = process1(&input);
-        println!("{}", result);
+        println!("{:?}", result);
     }
 
     #[test]
     fn test2_1() {
         let result = process2(TEST_GRAPH1);
-        assert_eq!(result, 36);
+        assert_eq!(result, Ok(36));
     }
 
     #[test]
     fn test2_2() {
         let result = process2(TEST_GRAPH2);
-        assert_eq!(result, 103);
+        assert_eq!(result, Ok(103));
     }
 
     #[test]
     fn test2_3() {
         let result = process2(TEST_GRAPH3);
-        assert_eq!(result, 3509);
+        assert_eq!(result, Ok(3509));
     }
 
     #[test]
     fn run2() {
         let input = read_to_string(INPUT_FILE);
         let result = process2(&input);
-        println!("{}", result);
+        println!("{:?}", result);
+    }
+
+    #[test]
+    fn lazy_paths() {
+        let graph = Graph::from_str(TEST_GRAPH1).unwrap();
+        let indexed = graph.index();
+        let paths = indexed.paths(&VisitPolicy::small_once()).collect_vec();
+        assert_eq!(paths.len(), 10);
+        assert!(paths.iter().all(|path| path.first() == Some(&Node::Start) && path.last() == Some(&Node::End)));
+        assert_eq!(paths[0].iter().join(","), "start,A,end");
+        assert_eq!(indexed.paths(&VisitPolicy::one_small_twice()).take(5).count(), 5);
+    }
+
+    #[test]
+    fn visit_policies() {
+        let indexed = Graph::from_str(TEST_GRAPH1).unwrap().index();
+        let small = |name: &str| Node::Small(name.to_owned());
+        let cases = [
+            (VisitPolicy::small_once(), 10),
+            (VisitPolicy::one_small_twice(), 36),
+            (VisitPolicy::small_once().forbid(small("b")), 2),
+            (VisitPolicy::small_once().require(small("c")), 5),
+            (VisitPolicy::small_once().require(small("d")), 0),
+            (VisitPolicy::small_once().require(small("c")).require(small("b")), 4),
+            (VisitPolicy::small_once().with_limit(small("c"), 2), 16),
+            (VisitPolicy::small_once().forbid(Node::Big("A".to_owned())), 1),
+            (VisitPolicy::small_once().forbid(Node::End), 0),
+        ];
+        for (policy, expected) in cases {
+            assert_eq!(indexed.count_paths(&policy), expected, "{:?}", policy);
+            assert_eq!(indexed.paths(&policy).count() as u64, expected, "{:?}", policy);
+        }
+
+        for input in [TEST_GRAPH1, TEST_GRAPH2, TEST_GRAPH3] {
+            let indexed = Graph::from_str(input).unwrap().index();
+            for policy in [VisitPolicy::one_small_twice().with_extra(2, 2), VisitPolicy::small_once().with_extra(1, 3)] {
+                assert_eq!(indexed.count_paths(&policy), indexed.paths(&policy).count() as u64);
+            }
+        }
+
+        // Too many small caves to pack the visit counts, so counting memoises on the unpacked counts.
+        let wide = (0..130).map(|i| format!("start-c{}\nc{}-end", i, i)).join("\n");
+        let indexed = Graph::from_str(&wide).unwrap().index();
+        assert!(indexed.walk(&VisitPolicy::small_once()).shifts.is_none());
+        assert_eq!(indexed.count_paths(&VisitPolicy::small_once()), 130);
+        // A chain of small hubs joined through pairs of big caves, padded with unreachable small
+        // caves so the counts don't pack. Only memoised counting gets through 2^40 paths.
+        let chain = |layers: usize| {
+            let mut edges = vec!["start-h0".to_string(), format!("h{}-end", layers)];
+            for i in 0..layers {
+                for big in ["X", "Y"] {
+                    edges.push(format!("h{}-{}{}", i, big, i));
+                    edges.push(format!("{}{}-h{}", big, i, i + 1));
+                }
+            }
+            edges.extend((0..70).map(|i| format!("end-pad{}", i)));
+            Graph::from_str(&edges.join("\n")).unwrap().index()
+        };
+        let short = chain(6);
+        assert!(short.walk(&VisitPolicy::one_small_twice()).shifts.is_none());
+        assert_eq!(short.count_paths(&VisitPolicy::one_small_twice()), short.paths(&VisitPolicy::one_small_twice()).count() as u64);
+        let long = chain(40);
+        assert!(long.walk(&VisitPolicy::one_small_twice()).shifts.is_none());
+        assert!(long.count_paths(&VisitPolicy::one_small_twice()) > 1 << 40);
+        assert!(Graph::from_str(TEST_GRAPH3).unwrap().index().walk(&VisitPolicy::one_small_twice()).shifts.is_some());
+    }
+
+    #[test]
+    fn infinite_paths() {
+        let big = |name: &str| Node::Big(name.to_owned());
+        let result = Graph::from_str("start-A\nA-B\nB-end");
+        assert_eq!(result.err(), Some(vec![GraphError::InfinitePaths(big("A"), big("B"))]));
+
+        let result = process2("start-a\na-C\nC-D\nD-end\nC-end");
+        assert_eq!(
+            result.unwrap_err()[0].to_string(),
+            "edge C-D connects two big caves on the way from start to end, so there are infinitely many paths"
+        );
+
+        assert!(Graph::from_str("start-a\na-end\nB-C\nC-x").is_ok());
+
+    }
+
+    #[test]
+    fn dot() {
+        let graph = Graph::from_str("start-A\nA-b\nb-end\nA-end").unwrap();
+        let expected = r#"graph caves {
+    "start" [shape=doublecircle, style=filled, fillcolor=palegreen];
+    "end" [shape=doublecircle, style=filled, fillcolor=lightcoral];
+    "A" [shape=box, style=filled, fillcolor=lightblue];
+    "b" [shape=ellipse];
+    "start" -- "A";
+    "end" -- "A";
+    "end" -- "b";
+    "A" -- "b";
+}
+"#;
+        assert_eq!(graph.to_dot(None), expected);
+
+        let path = ["start", "A", "b", "end"].map(Node::from_str);
+        let dot = graph.to_dot(Some(&path));
+        assert!(dot.contains("\"b\" [shape=ellipse, color=red, penwidth=3];"));
+        assert!(dot.contains("\"end\" -- \"b\" [color=red, penwidth=3];"));
+        assert!(dot.contains("\"end\" -- \"A\";"));
+
+        let graph = Graph::from_str("start-a\"b\na\"b-c\\d\nc\\d-end").unwrap();
+        let dot = graph.to_dot(None);
+        assert!(dot.contains(r#"    "a\"b" [shape=ellipse];"#));
+        assert!(dot.contains(r#"    "a\"b" -- "c\\d";"#));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn weighted_paths() {
+        let graph = Graph::from_str("start-A:2\nstart-b:5\nA-b:1\nA-end:10\nb-end:2\nb-c").unwrap();
+        let indexed = graph.index();
+        let names = |path: &[Node]| path.iter().join(",");
+
+        let (cost, path) = indexed.shortest_path().unwrap();
+        assert_eq!((cost, names(&path).as_str()), (5, "start,A,b,end"));
+
+        let shortest = indexed.k_shortest_paths(&VisitPolicy::small_once(), 4).into_iter()
+            .map(|(cost, path)| (cost, names(&path)))
+            .collect_vec();
+        assert_eq!(shortest, vec![
+            (5, "start,A,b,end".to_owned()),
+            (7, "start,b,end".to_owned()),
+            (12, "start,A,end".to_owned()),
+            (14, "start,A,b,A,end".to_owned()),
+        ]);
+        assert_eq!(indexed.k_shortest_paths(&VisitPolicy::small_once(), 100).len(), indexed.paths(&VisitPolicy::small_once()).count());
+
+        let policy = VisitPolicy::one_small_twice().require(Node::from_str("c"));
+        let (cost, path) = indexed.k_shortest_paths(&policy, 1).remove(0);
+        assert_eq!((cost, names(&path).as_str()), (7, "start,A,b,c,b,end"));
+        assert!(indexed.k_shortest_paths(&VisitPolicy::small_once().require(Node::from_str("c")), 1).is_empty());
+
+        let (cost, path) = indexed.longest_path(&VisitPolicy::small_once()).unwrap();
+        assert_eq!((cost, names(&path).as_str()), (16, "start,b,A,end"));
+        assert_eq!(indexed.path_cost(&path), 16);
+        assert!(indexed.k_shortest_paths(&VisitPolicy::small_once(), 0).is_empty());
+
+        let huge = Graph::from_str("start-a:18446744073709551615\na-end:1\nstart-b:3\nb-end:4").unwrap().index();
+        let (cost, path) = huge.shortest_path().unwrap();
+        assert_eq!((cost, names(&path).as_str()), (7, "start,b,end"));
+        let costs = huge.k_shortest_paths(&VisitPolicy::small_once(), 5).into_iter().map(|(cost, _)| cost).collect_vec();
+        assert_eq!(costs, vec![7, u64::MAX as u128 + 1]);
+        let overflowing = Graph::from_str("start-a:18446744073709551615\na-end:1").unwrap().index();
+        let (cost, path) = overflowing.shortest_path().unwrap();
+        assert_eq!((cost, names(&path).as_str()), (u64::MAX as u128 + 1, "start,a,end"));
+        assert_eq!(overflowing.path_cost(&path), cost);
+    }
+
+    #[test]
+    fn validation() {
+        let input = "start-A\nA-\n\nA-b-c\nb-xY\nc-c\nA-b\nb-A\nc-d:x\nb-end";
+        let errors = Graph::from_str(input).err().unwrap().iter().map(|error| error.to_string()).collect_vec();
+        assert_eq!(errors, vec![
+            "line 2: empty cave name",
+            "line 4: expected an edge like `a-b` or `a-b:3`, found `A-b-c`",
+            "line 5: cave name `xY` mixes upper and lower case",
+            "line 6: cave c is connected to itself",
+            "line 8: edge b-A was already given on line 7",
+            "line 9: invalid edge weight `x`",
+        ]);
+
+        assert_eq!(Graph::from_str("a-b\nb-c").err(), Some(vec![
+            GraphError::MissingNode(Node::Start),
+            GraphError::MissingNode(Node::End),
+        ]));
+
+        let graph = Graph::from_str("start-A\nA-b\nb-c\nA-end\nd-E").unwrap();
+        let warnings = graph.warnings().iter().map(|warning| warning.to_string()).collect_vec();
+        assert_eq!(warnings, vec!["cave E cannot be reached from start", "cave d cannot be reached from start"]);
+
+        let graph = Graph::from_str("start-A\nA-b\nc-end").unwrap();
+        assert_eq!(graph.warnings(), vec![
+            GraphWarning::DeadEnd(Node::Start),
+            GraphWarning::Unreachable(Node::End),
+            GraphWarning::DeadEnd(Node::Big("A".to_owned())),
+            GraphWarning::DeadEnd(Node::Small("b".to_owned())),
+            GraphWarning::Unreachable(Node::Small("c".to_owned())),
+        ]);
+        assert!(Graph::from_str(TEST_GRAPH3).unwrap().warnings().is_empty());
+    }
+
+    #[test]
+    fn parallel() {
+        for input in [TEST_GRAPH1, TEST_GRAPH2] {
+            let indexed = Graph::from_str(input).unwrap().index();
+            for policy in [VisitPolicy::small_once(), VisitPolicy::one_small_twice(), VisitPolicy::small_once().with_extra(2, 3)] {
+                assert_eq!(indexed.par_count_paths(&policy), indexed.count_paths(&policy));
+                assert_eq!(
+                    indexed.par_paths(&policy).collect::<Vec<_>>().into_iter().sorted().collect_vec(),
+                    indexed.paths(&policy).sorted().collect_vec()
+                );
+            }
+        }
+
+        let indexed = Graph::from_str(TEST_GRAPH3).unwrap().index();
+        assert_eq!(
+            indexed.par_paths(&VisitPolicy::small_once()).collect::<Vec<_>>().into_iter().sorted().collect_vec(),
+            indexed.paths(&VisitPolicy::small_once()).sorted().collect_vec()
+        );
+        let policy = VisitPolicy::one_small_twice();
+        assert_eq!(indexed.par_count_paths(&policy), 3509);
+        assert_eq!(indexed.par_paths(&policy).count(), 3509);
+        let (open, complete) = indexed.frontier(&policy, 50);
+        assert!(open.len() >= 50);
+        let from_open: usize = open.iter()
+            .map(|prefix| indexed.paths_from(&policy, prefix).count())
+            .sum();
+        assert_eq!(from_open + complete.len(), 3509);
+    }
+}
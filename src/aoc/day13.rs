@@ -1,14 +1,90 @@
-use itertools::Itertools;
-use crate::aoc::day13::implementation::{Operation, Point, State};
-use crate::common::get_lines_iterator;
+use std::fmt::{Display, Formatter};
+use crate::aoc::day13::implementation::{InputError, Keep, OcrError, Operation, Point, State, Transform};
+use crate::common::{read_to_string, split_sections, Lcg};
 
-mod implementation {
+pub mod implementation {
     use std::borrow::BorrowMut;
     use std::collections::{BTreeSet, HashSet};
+    use std::fmt::{Display, Formatter};
+    use std::io::{self, Write};
+    use std::str::FromStr;
     use itertools::Itertools;
-    use crate::aoc::day13::Operation::FoldX;
+    use rayon::prelude::*;
+    use crate::common::Lcg;
 
-    #[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq)]
+    const GLYPH_WIDTH: i32 = 4;
+    const GLYPH_HEIGHT: i32 = 6;
+    // Letters are separated by one empty column.
+    const GLYPH_PITCH: i32 = GLYPH_WIDTH + 1;
+
+    const FONT: [(char, &str); 16] = [
+        ('A', ".##.#..##..######..##..#"),
+        ('B', "###.#..####.#..##..####."),
+        ('C', ".##.#..##...#...#..#.##."),
+        ('E', "#####...###.#...#...####"),
+        ('F', "#####...###.#...#...#..."),
+        ('G', ".##.#..##...#.###..#.###"),
+        ('H', "#..##..######..##..##..#"),
+        ('J', "..##...#...#...##..#.##."),
+        ('K', "#..##.#.##..#.#.#.#.#..#"),
+        ('L', "#...#...#...#...#...####"),
+        ('O', ".##.#..##..##..##..#.##."),
+        ('P', "###.#..##..####.#...#..."),
+        ('R', "###.#..##..####.#.#.#..#"),
+        ('S', ".####...#....##....####."),
+        ('U', "#..##..##..##..##..#.##."),
+        ('Z', "####...#..#..#..#...####"),
+    ];
+
+    #[derive(Debug, PartialEq)]
+    pub enum InputError {
+        MissingDots,
+        MissingFolds,
+        MalformedDot { line: usize, text: String },
+        MalformedFold { line: usize, text: String },
+        ExtraSection { line: usize },
+    }
+
+    impl Display for InputError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                InputError::MissingDots => write!(f, "no dots section"),
+                InputError::MissingFolds => write!(f, "no fold instructions after the dots"),
+                InputError::MalformedDot { line, text } => write!(f, "line {}: expected `x,y`, found `{}`", line, text),
+                InputError::MalformedFold { line, text } => write!(f, "line {}: unknown fold instruction `{}`", line, text),
+                InputError::ExtraSection { line } => write!(f, "line {}: unexpected section after the folds", line),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum OcrError {
+        Empty,
+        // Indices of the letter cells, counted from the left, that match no glyph.
+        Unrecognised(Vec<usize>),
+    }
+
+    impl Display for OcrError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                OcrError::Empty => write!(f, "no dots to read"),
+                OcrError::Unrecognised(positions) => write!(
+                    f,
+                    "unrecognised glyphs at positions {}",
+                    positions.iter().join(", ")
+                ),
+            }
+        }
+    }
+
+    fn glyph_bits(pattern: &str) -> u32 {
+        pattern.chars()
+            .enumerate()
+            .filter(|(_, ch)| *ch == '#')
+            .fold(0, |bits, (i, _)| bits | 1 << i)
+    }
+
+    #[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Debug)]
     pub struct Point {
         x: i32,
         y: i32,
@@ -19,31 +95,307 @@ mod implementation {
             Point { x, y }
         }
 
-        pub fn from_str(s: &str) -> Point {
-            let (x, y) = s.trim().split_once(",").unwrap();
-            let x = x.parse::<i32>().unwrap();
-            let y = y.parse::<i32>().unwrap();
-            Point::new(x, y)
+        pub fn x(&self) -> i32 {
+            self.x
+        }
+
+        pub fn y(&self) -> i32 {
+            self.y
+        }
+
+        pub fn parse(s: &str) -> Option<Point> {
+            let (x, y) = s.trim().split_once(',')?;
+            Some(Point::new(x.trim().parse().ok()?, y.trim().parse().ok()?))
+        }
+    }
+
+    impl FromStr for Point {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Point::parse(s).ok_or_else(|| format!("Malformed dot: {}", s))
+        }
+    }
+
+    // Inclusive on both corners.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub struct BoundingBox {
+        pub min: Point,
+        pub max: Point,
+    }
+
+    impl BoundingBox {
+        pub fn width(&self) -> usize {
+            (self.max.x - self.min.x + 1) as usize
+        }
+
+        pub fn height(&self) -> usize {
+            (self.max.y - self.min.y + 1) as usize
+        }
+    }
+
+    pub trait Renderer {
+        fn write(&self, state: &State, out: &mut dyn Write) -> io::Result<()>;
+
+        fn render(&self, state: &State) -> String {
+            let mut out = Vec::new();
+            self.write(state, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        }
+    }
+
+    // One character per dot.
+    pub struct Ascii {
+        pub on: char,
+        pub off: char,
+    }
+
+    impl Default for Ascii {
+        fn default() -> Self {
+            Ascii { on: '#', off: '.' }
+        }
+    }
+
+    impl Renderer for Ascii {
+        fn write(&self, state: &State, out: &mut dyn Write) -> io::Result<()> {
+            let Some(bounds) = state.bounding_box() else { return Ok(()) };
+            for y in bounds.min.y..=bounds.max.y {
+                let line: String = (bounds.min.x..=bounds.max.x)
+                    .map(|x| if state.contains(x, y) { self.on } else { self.off })
+                    .collect();
+                writeln!(out, "{}", line)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Half blocks, two rows of dots per line.
+    pub struct Blocks;
+
+    impl Renderer for Blocks {
+        fn write(&self, state: &State, out: &mut dyn Write) -> io::Result<()> {
+            let Some(bounds) = state.bounding_box() else { return Ok(()) };
+            for y in (bounds.min.y..=bounds.max.y).step_by(2) {
+                let line: String = (bounds.min.x..=bounds.max.x)
+                    .map(|x| match (state.contains(x, y), state.contains(x, y + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    })
+                    .collect();
+                writeln!(out, "{}", line)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Braille patterns, a 2x4 block of dots per character.
+    pub struct Braille;
+
+    impl Braille {
+        // Bit for each dot of a braille cell, indexed by [row][column].
+        const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+    }
+
+    impl Renderer for Braille {
+        fn write(&self, state: &State, out: &mut dyn Write) -> io::Result<()> {
+            let Some(bounds) = state.bounding_box() else { return Ok(()) };
+            for y in (bounds.min.y..=bounds.max.y).step_by(4) {
+                let line: String = (bounds.min.x..=bounds.max.x).step_by(2)
+                    .map(|x| {
+                        let bits = (0..4).cartesian_product(0..2)
+                            .filter(|&(dy, dx)| state.contains(x + dx, y + dy))
+                            .fold(0, |bits, (dy, dx)| bits | Braille::DOTS[dy as usize][dx as usize]);
+                        char::from_u32(0x2800 + bits).unwrap()
+                    })
+                    .collect();
+                writeln!(out, "{}", line)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Plain PBM bitmap, one pixel per dot.
+    pub struct Pbm;
+
+    impl Renderer for Pbm {
+        fn write(&self, state: &State, out: &mut dyn Write) -> io::Result<()> {
+            let Some(bounds) = state.bounding_box() else { return writeln!(out, "P1\n0 0") };
+            writeln!(out, "P1\n{} {}", bounds.width(), bounds.height())?;
+            for y in bounds.min.y..=bounds.max.y {
+                let row = (bounds.min.x..=bounds.max.x)
+                    .map(|x| if state.contains(x, y) { '1' } else { '0' })
+                    .join(" ");
+                writeln!(out, "{}", row)?;
+            }
+            Ok(())
         }
     }
 
+    // Which side of the fold line stays put. `Low` is the side with smaller coordinates: left of a
+    // vertical line, above a horizontal one, and above-right of a diagonal one.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum Keep {
+        Low,
+        High,
+    }
+
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
     pub enum Operation {
-        FoldX(i32),
-        FoldY(i32),
+        FoldX(i32, Keep),
+        FoldY(i32, Keep),
+        // Along the line y = x + c.
+        FoldDiagonal(i32, Keep),
     }
 
     impl Operation {
-        pub fn from_str(s: &str) -> Self {
-            let (direction, n) = s.trim().split_once("=").unwrap();
-            let n = n.parse::<i32>().unwrap();
-            match direction.trim() {
-                "fold along x" => Operation::FoldX(n),
-                "fold along y" => Operation::FoldY(n),
-                _ => panic!("Unknown operation: {}", direction),
+        // Accepts `fold [left|right|up|down] along x=N`, `y=N` or `y=x+C`. Without a direction the
+        // high side folds onto the low one.
+        pub fn parse(s: &str) -> Option<Self> {
+            let words = s.split_whitespace().collect_vec();
+            let (direction, line) = match words[..] {
+                ["fold", "along", line] => (None, line),
+                ["fold", direction, "along", line] => (Some(direction), line),
+                _ => return None,
+            };
+            let (axis, n) = line.split_once('=')?;
+            let (axis, n) = match (axis, n.strip_prefix('x')) {
+                ("y", Some("")) => ("y=x", 0),
+                ("y", Some(c)) => ("y=x", c.parse::<i32>().ok()?),
+                _ => (axis, n.parse::<i32>().ok()?),
+            };
+            match (axis, direction) {
+                ("x", None | Some("left")) => Some(Operation::FoldX(n, Keep::Low)),
+                ("x", Some("right")) => Some(Operation::FoldX(n, Keep::High)),
+                ("y", None | Some("up")) => Some(Operation::FoldY(n, Keep::Low)),
+                ("y", Some("down")) => Some(Operation::FoldY(n, Keep::High)),
+                ("y=x", None | Some("up")) => Some(Operation::FoldDiagonal(n, Keep::Low)),
+                ("y=x", Some("down")) => Some(Operation::FoldDiagonal(n, Keep::High)),
+                _ => None,
+            }
+        }
+
+        fn keep(&self) -> Keep {
+            match *self {
+                Operation::FoldX(_, keep) | Operation::FoldY(_, keep) | Operation::FoldDiagonal(_, keep) => keep,
+            }
+        }
+
+        // Positive for points that move, zero for points on the line and negative for those that stay.
+        fn side(&self, point: &Point) -> i64 {
+            let (x, y) = (point.x as i64, point.y as i64);
+            let distance = match *self {
+                Operation::FoldX(line, _) => x - line as i64,
+                Operation::FoldY(line, _) => y - line as i64,
+                Operation::FoldDiagonal(c, _) => y - x - c as i64,
+            };
+            match self.keep() {
+                Keep::Low => distance,
+                Keep::High => -distance,
             }
         }
+
+        // None if the mirrored point falls outside i32 coordinates.
+        fn reflect(&self, point: &Point) -> Option<Point> {
+            let (x, y) = (point.x as i64, point.y as i64);
+            let (x, y) = match *self {
+                Operation::FoldX(line, _) => (2 * line as i64 - x, y),
+                Operation::FoldY(line, _) => (x, 2 * line as i64 - y),
+                Operation::FoldDiagonal(c, _) => (y - c as i64, x + c as i64),
+            };
+            Some(Point::new(i32::try_from(x).ok()?, i32::try_from(y).ok()?))
+        }
+    }
+
+    impl FromStr for Operation {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Operation::parse(s).ok_or_else(|| format!("Unknown operation: {}", s))
+        }
+    }
+
+    impl Display for Operation {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match *self {
+                Operation::FoldX(x, Keep::Low) => write!(f, "fold along x={}", x),
+                Operation::FoldX(x, Keep::High) => write!(f, "fold right along x={}", x),
+                Operation::FoldY(y, Keep::Low) => write!(f, "fold along y={}", y),
+                Operation::FoldY(y, Keep::High) => write!(f, "fold down along y={}", y),
+                Operation::FoldDiagonal(c, Keep::Low) => write!(f, "fold along y=x{:+}", c),
+                Operation::FoldDiagonal(c, Keep::High) => write!(f, "fold down along y=x{:+}", c),
+            }
+        }
+    }
+
+    fn letter_points(letter: char, left: i32) -> Option<Vec<Point>> {
+        let (_, pattern) = FONT.iter().find(|(glyph, _)| *glyph == letter)?;
+        let points = pattern.chars()
+            .positions(|ch| ch == '#')
+            .map(|i| Point::new(left + i as i32 % GLYPH_WIDTH, i as i32 / GLYPH_WIDTH))
+            .collect();
+        Some(points)
+    }
+
+    // A whole fold sequence as one piecewise reflection, so each dot is mapped independently.
+    pub struct Transform {
+        operations: Vec<Operation>,
     }
 
+    impl Transform {
+        pub fn compose(operations: &[Operation]) -> Transform {
+            Transform { operations: operations.to_vec() }
+        }
+
+        // None if the dot lands on a fold line at some point or is mirrored out of i32 range.
+        pub fn apply(&self, point: Point) -> Option<Point> {
+            self.operations.iter().try_fold(point, |point, operation| {
+                match operation.side(&point) {
+                    0 => None,
+                    side if side > 0 => operation.reflect(&point),
+                    _ => Some(point),
+                }
+            })
+        }
+
+        // One dot that ends up on `point`, unfolding to either side of each line at random. None if
+        // the chosen unfolding crosses a line it could not have come from or leaves i32 range.
+        pub fn sample_preimage(&self, point: Point, random: &mut Lcg) -> Option<Point> {
+            self.operations.iter().rev().try_fold(point, |point, operation| {
+                if operation.side(&point) >= 0 {
+                    None
+                } else if random.below(2) == 0 {
+                    Some(point)
+                } else {
+                    operation.reflect(&point)
+                }
+            })
+        }
+
+        // Every dot that ends up on `point`, empty if nothing can.
+        pub fn preimages(&self, point: Point) -> Vec<Point> {
+            self.operations.iter().rev().fold(vec![point], |points, operation| {
+                points.into_iter()
+                    .filter(|point| operation.side(point) < 0)
+                    .flat_map(|point| [Some(point), operation.reflect(&point)].into_iter().flatten())
+                    .collect()
+            })
+        }
+    }
+
+    #[derive(PartialEq, Debug)]
+    pub struct FoldStep {
+        pub operation: Operation,
+        pub dots_before: usize,
+        pub dots_after: usize,
+        // Moved dots that landed on a dot already there.
+        pub overlaps: usize,
+        pub bounding_box: Option<BoundingBox>,
+        pub rendering: Option<String>,
+    }
+
+    #[derive(PartialEq, Debug)]
     pub struct State {
         points: BTreeSet<Point>,
     }
@@ -54,111 +406,287 @@ mod implementation {
             State { points }
         }
 
-        pub fn execute_operation(&mut self, operation: Operation) {
-            match operation {
-                Operation::FoldX(x) => self.fold_x(x),
-                Operation::FoldY(y) => self.fold_y(y),
+        // Lays out a message in the 4x6 font from the origin, or returns the first unknown letter.
+        pub fn from_letters(message: &str) -> Result<State, char> {
+            let mut points = Vec::new();
+            for (i, letter) in message.chars().enumerate() {
+                points.extend(letter_points(letter, i as i32 * GLYPH_PITCH).ok_or(letter)?);
             }
+            Ok(State::from_points(&points))
         }
 
-        pub fn get_point_count(&self) -> usize {
-            self.points.len()
+        // The smallest sheet that folds into this one: a single dot per target dot. Fails with the
+        // first dot that no sheet can produce.
+        pub fn unfold(&self, operations: &[Operation]) -> Result<State, Point> {
+            let transform = Transform::compose(operations);
+            let points = self.points.iter()
+                .map(|&point| transform.preimages(point).first().cloned().ok_or(point))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(State::from_points(&points))
         }
 
-        pub fn draw(&self) {
-            let max_x = self.points.iter()
-                .max_by_key(|point| point.x)
-                .map(|point| point.x)
-                .unwrap();
-            let max_y = self.points.iter()
-                .max_by_key(|point| point.y)
-                .map(|point| point.y)
-                .unwrap();
-
-            for y in 0..=max_y {
-                for x in 0..=max_x {
-                    let ch = if self.points.contains(&Point::new(x,y)) { '#' } else { '.' };
-                    print!("{}", ch);
-                }
-                println!();
+        // Every crease-free sheet that folds into this one: a non-empty choice of pre-images for
+        // each dot. Sheets with extra dots on a fold line fold into it too but are not listed.
+        pub fn unfoldings(&self, operations: &[Operation]) -> Result<Box<dyn Iterator<Item = State>>, Point> {
+            let transform = Transform::compose(operations);
+            let choices = self.points.iter()
+                .map(|&point| {
+                    let preimages = transform.preimages(point);
+                    if preimages.is_empty() { Err(point) } else { Ok(preimages.into_iter().powerset().skip(1)) }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if choices.is_empty() {
+                return Ok(Box::new(std::iter::once(State::from_points(&[]))));
             }
+            let sheets = choices.into_iter()
+                .multi_cartesian_product()
+                .map(|choice| State::from_points(&choice.concat()));
+            Ok(Box::new(sheets))
         }
 
-        fn fold_x(&mut self, x: i32) {
+        // Dots on the fold line are lost in the crease, as are dots mirrored out of i32 range.
+        pub fn execute_operation(&mut self, operation: Operation) {
             let points = self.points.borrow_mut();
-            let to_flip = points.iter().filter(|point| point.x > x).cloned().collect_vec();
-            for point in &to_flip {
+            let affected = points.iter().filter(|point| operation.side(point) >= 0).cloned().collect_vec();
+            for point in &affected {
                 points.remove(point);
             }
 
-            to_flip.into_iter()
-                .map(|point| Point::new(x - (point.x - x), point.y))
+            affected.into_iter()
+                .filter(|point| operation.side(point) > 0)
+                .filter_map(|point| operation.reflect(&point))
                 .for_each(|point| {
                     points.insert(point);
                 })
         }
 
-        fn fold_y(&mut self, y: i32) {
-            let points = self.points.borrow_mut();
-            let to_flip = points.iter().filter(|point| point.y > y).cloned().collect_vec();
-            for point in &to_flip {
-                points.remove(point);
+        // Same result as executing each operation in turn.
+        pub fn execute_all(&mut self, operations: &[Operation]) {
+            let transform = Transform::compose(operations);
+            self.points = self.points.par_iter()
+                .filter_map(|&point| transform.apply(point))
+                .collect();
+        }
+
+        // Executes the operations one at a time as the iterator is advanced, describing each fold
+        // and optionally rendering the sheet it leaves behind.
+        pub fn trace<'a>(
+            &'a mut self,
+            operations: &'a [Operation],
+            renderer: Option<&'a dyn Renderer>,
+        ) -> impl Iterator<Item = FoldStep> + 'a {
+            operations.iter().map(move |&operation| {
+                let dots_before = self.points.len();
+                let moved = self.points.iter()
+                    .filter(|point| operation.side(point) > 0 && operation.reflect(point).is_some())
+                    .count();
+                let stayed = self.points.iter().filter(|point| operation.side(point) < 0).count();
+                self.execute_operation(operation);
+                FoldStep {
+                    operation,
+                    dots_before,
+                    dots_after: self.points.len(),
+                    overlaps: stayed + moved - self.points.len(),
+                    bounding_box: self.bounding_box(),
+                    rendering: renderer.map(|renderer| renderer.render(self)),
+                }
+            })
+        }
+
+        pub fn get_point_count(&self) -> usize {
+            self.points.len()
+        }
+
+        // Reads the dots as a row of 4x6 letters, laid out in 5 column cells from the top left dot.
+        pub fn read_letters(&self) -> Result<String, OcrError> {
+            let min_x = self.points.iter().map(|point| point.x).min().ok_or(OcrError::Empty)?;
+            let min_y = self.points.iter().map(|point| point.y).min().unwrap();
+            let max_x = self.points.iter().map(|point| point.x).max().unwrap();
+            let cells = ((max_x - min_x) / GLYPH_PITCH + 1) as usize;
+
+            let mut bits = vec![0u32; cells];
+            let mut stray = vec![false; cells];
+            for point in &self.points {
+                let (x, y) = (point.x - min_x, point.y - min_y);
+                let cell = (x / GLYPH_PITCH) as usize;
+                let column = x % GLYPH_PITCH;
+                if column < GLYPH_WIDTH && y < GLYPH_HEIGHT {
+                    bits[cell] |= 1 << (y * GLYPH_WIDTH + column);
+                } else {
+                    stray[cell] = true;
+                }
             }
 
-            to_flip.into_iter()
-                .map(|point| Point::new(point.x, y - (point.y - y)))
-                .for_each(|point| {
-                    points.insert(point);
+            let letters = bits.iter().zip(&stray)
+                .map(|(&bits, &stray)| {
+                    FONT.iter()
+                        .find(|(_, pattern)| !stray && glyph_bits(pattern) == bits)
+                        .map(|(letter, _)| *letter)
                 })
+                .collect_vec();
+            let unrecognised = letters.iter().positions(|letter| letter.is_none()).collect_vec();
+            if unrecognised.is_empty() {
+                Ok(letters.into_iter().flatten().collect())
+            } else {
+                Err(OcrError::Unrecognised(unrecognised))
+            }
+        }
+
+        pub fn points(&self) -> impl Iterator<Item = &Point> {
+            self.points.iter()
+        }
+
+        pub fn contains(&self, x: i32, y: i32) -> bool {
+            self.points.contains(&Point::new(x, y))
+        }
+
+        pub fn bounding_box(&self) -> Option<BoundingBox> {
+            let (min_x, max_x) = self.points.iter().map(|point| point.x).minmax().into_option()?;
+            let (min_y, max_y) = self.points.iter().map(|point| point.y).minmax().into_option()?;
+            Some(BoundingBox { min: Point::new(min_x, min_y), max: Point::new(max_x, max_y) })
+        }
+
+        pub fn draw(&self) {
+            print!("{}", Ascii::default().render(self));
         }
+
     }
 }
 
-fn process1(file_name: &str) -> usize {
-    let mut lines = get_lines_iterator(file_name).map(|line| line.unwrap()).collect_vec();
-    let mut split = lines.split_inclusive(|line| line.is_empty());
-    let point_lines = split.next().unwrap();
-    let operation_lines = split.next().unwrap();
+#[derive(Debug, PartialEq)]
+pub enum GeneratorError {
+    EmptyMessage,
+    // Puzzle inputs always end with a fold section.
+    NoFolds,
+    UnknownLetter(char),
+    // The unfolded sheet would not fit in i32 coordinates.
+    TooManyFolds,
+}
 
-    let points = point_lines.into_iter()
-        .filter(|line| !line.is_empty())
-        .map(|line| Point::from_str(line))
-        .collect_vec();
-    let mut state = State::from_points(&points);
+impl Display for GeneratorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeneratorError::EmptyMessage => write!(f, "nothing to write"),
+            GeneratorError::NoFolds => write!(f, "at least one fold is needed"),
+            GeneratorError::UnknownLetter(letter) => write!(f, "no glyph for {:?}", letter),
+            GeneratorError::TooManyFolds => write!(f, "too many folds for i32 coordinates"),
+        }
+    }
+}
+
+// Writes a puzzle input whose dots fold into `message`. The sheet is doubled `folds` times,
+// alternating axes, and each letter dot is unfolded to one to three random pre-images.
+pub fn generate_puzzle(message: &str, folds: usize, seed: u64) -> Result<String, GeneratorError> {
+    let target = State::from_letters(message).map_err(GeneratorError::UnknownLetter)?;
+    let bounds = target.bounding_box().ok_or(GeneratorError::EmptyMessage)?;
+    if folds == 0 {
+        return Err(GeneratorError::NoFolds);
+    }
+    let (mut width, mut height) = (bounds.max.x() + 1, bounds.max.y() + 1);
+    let doubled = |size: i32| size.checked_mul(2).and_then(|size| size.checked_add(1)).ok_or(GeneratorError::TooManyFolds);
+    let mut operations = Vec::new();
+    for i in 0..folds {
+        if i % 2 == 0 {
+            operations.push(Operation::FoldX(width, Keep::Low));
+            width = doubled(width)?;
+        } else {
+            operations.push(Operation::FoldY(height, Keep::Low));
+            height = doubled(height)?;
+        }
+    }
+    operations.reverse();
 
-    operation_lines.into_iter()
-        .map(|line| Operation::from_str(&line))
-        .take(1)
-        .for_each(|command| {
-            state.execute_operation(command);
-        });
-    state.get_point_count()
+    // Each fold line lies beyond everything unfolded by the later folds, so no unfolding can
+    // cross a line it came from.
+    let mut random = Lcg::new(seed);
+    let transform = Transform::compose(&operations);
+    let mut points = Vec::new();
+    for &point in target.points() {
+        let picks = 1 + random.below(3);
+        points.extend((0..picks).map(|_| transform.sample_preimage(point, &mut random).unwrap()));
+    }
+
+    let dots = State::from_points(&points);
+    let mut puzzle = dots.points()
+        .map(|point| format!("{},{}\n", point.x(), point.y()))
+        .collect::<String>();
+    puzzle.push('\n');
+    for operation in &operations {
+        puzzle.push_str(&format!("{}\n", operation));
+    }
+    Ok(puzzle)
+}
+
+#[derive(Debug, PartialEq)]
+enum PuzzleError {
+    Input(InputError),
+    Ocr(OcrError),
+}
+
+impl From<InputError> for PuzzleError {
+    fn from(error: InputError) -> Self {
+        PuzzleError::Input(error)
+    }
+}
+
+impl From<OcrError> for PuzzleError {
+    fn from(error: OcrError) -> Self {
+        PuzzleError::Ocr(error)
+    }
 }
 
-fn process2(file_name: &str) {
-    let mut lines = get_lines_iterator(file_name).map(|line| line.unwrap()).collect_vec();
-    let mut split = lines.split_inclusive(|line| line.is_empty());
-    let point_lines = split.next().unwrap();
-    let operation_lines = split.next().unwrap();
+impl Display for PuzzleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PuzzleError::Input(error) => write!(f, "{}", error),
+            PuzzleError::Ocr(error) => write!(f, "{}", error),
+        }
+    }
+}
 
-    let points = point_lines.into_iter()
-        .filter(|line| !line.is_empty())
-        .map(|line| Point::from_str(line))
-        .collect_vec();
+pub fn parse_input(text: &str) -> Result<(Vec<Point>, Vec<Operation>), InputError> {
+    let sections = split_sections(text);
+    let (dots, folds) = match &sections[..] {
+        [] => return Err(InputError::MissingDots),
+        // A lone section is whichever kind its first line looks like.
+        [only] if only[0].1.starts_with("fold") => return Err(InputError::MissingDots),
+        [_] => return Err(InputError::MissingFolds),
+        [dots, folds] => (dots, folds),
+        [_, _, extra, ..] => return Err(InputError::ExtraSection { line: extra[0].0 }),
+    };
+
+    let points = dots.iter()
+        .map(|&(line, text)| Point::parse(text).ok_or_else(|| InputError::MalformedDot { line, text: text.to_string() }))
+        .collect::<Result<Vec<_>, _>>()?;
+    let operations = folds.iter()
+        .map(|&(line, text)| Operation::parse(text).ok_or_else(|| InputError::MalformedFold { line, text: text.to_string() }))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((points, operations))
+}
+
+fn process1(file_name: &str) -> Result<usize, InputError> {
+    let (points, operations) = parse_input(&read_to_string(file_name))?;
     let mut state = State::from_points(&points);
+    if let Some(&operation) = operations.first() {
+        state.execute_operation(operation);
+    }
+    Ok(state.get_point_count())
+}
 
-    operation_lines.into_iter()
-        .map(|line| Operation::from_str(&line))
-        .for_each(|command| {
-            state.execute_operation(command);
-        });
-    state.draw();
+fn process2(file_name: &str) -> Result<String, PuzzleError> {
+    let (points, operations) = parse_input(&read_to_string(file_name))?;
+    let mut state = State::from_points(&points);
+    state.execute_all(&operations);
+    Ok(state.read_letters()?)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::aoc::day13::process2;
+    use itertools::Itertools;
+    use crate::aoc::day13::{generate_puzzle, parse_input, process2, GeneratorError, PuzzleError};
     use super::process1;
+    use super::implementation::{InputError, Ascii, Blocks, BoundingBox, Braille, FoldStep, Keep, OcrError, Operation, Pbm, Point, Renderer, State, Transform};
+    use crate::common::Lcg;
 
     const TEST_FILE: &'static str = "input/test13";
     const INPUT_FILE: &'static str = "input/input13";
@@ -166,17 +694,305 @@ mod tests {
     #[test]
     fn test() {
         let result = process1(TEST_FILE);
-        assert_eq!(result, 17);
+        assert_eq!(result, Ok(17));
     }
 
     #[test]
     fn run() {
         let result = process1(INPUT_FILE);
-        println!("{}", result);
+        println!("{}", result.unwrap());
     }
 
     #[test]
     fn run2() {
-        process2(INPUT_FILE);
+        let result = process2(INPUT_FILE);
+        println!("{}", result.unwrap());
     }
-}
\ No newline at end of file
+
+    fn sheet(art: &str) -> State {
+        let points = art.lines()
+            .enumerate()
+            .flat_map(|(y, line)| {
+                line.chars()
+                    .positions(|ch| ch == '#')
+                    .map(move |x| Point::new(x as i32, y as i32))
+            })
+            .collect_vec();
+        State::from_points(&points)
+    }
+
+    #[test]
+    fn ocr() {
+        let state = sheet(concat!(
+            "#..#.####.#....#.....##.\n",
+            "#..#.#....#....#....#..#\n",
+            "####.###..#....#....#..#\n",
+            "#..#.#....#....#....#..#\n",
+            "#..#.#....#....#....#..#\n",
+            "#..#.####.####.####..##.\n",
+        ));
+        assert_eq!(state.read_letters(), Ok("HELLO".to_string()));
+
+        let state = sheet(concat!(
+            ".##..#..#.###.\n",
+            "#..#.#..#.#..#\n",
+            "#..#.####.#..#\n",
+            "####.#..#.###.\n",
+            "#..#.#..#.#...\n",
+            "#..#.#..#.#.#.\n",
+        ));
+        assert_eq!(state.read_letters(), Err(OcrError::Unrecognised(vec![2])));
+        assert_eq!(State::from_points(&[]).read_letters(), Err(OcrError::Empty));
+
+        // The example folds into a 5x5 square, which spills into the gap column.
+        assert_eq!(process2(TEST_FILE), Err(PuzzleError::Ocr(OcrError::Unrecognised(vec![0]))));
+    }
+
+    #[test]
+    fn renderers() {
+        let state = State::from_points(&[Point::new(-2, -1), Point::new(0, 1), Point::new(1, -1)]);
+        assert_eq!(
+            state.bounding_box(),
+            Some(BoundingBox { min: Point::new(-2, -1), max: Point::new(1, 1) })
+        );
+        assert_eq!(Ascii::default().render(&state), "#..#\n....\n..#.\n");
+        assert_eq!(Ascii { on: 'o', off: ' ' }.render(&state), "o  o\n    \n  o \n");
+        assert_eq!(Blocks.render(&state), "▀  ▀\n  ▀ \n");
+        assert_eq!(Braille.render(&state), "⠁⠌\n");
+        assert_eq!(Pbm.render(&state), "P1\n4 3\n1 0 0 1\n0 0 0 0\n0 0 1 0\n");
+
+        let empty = State::from_points(&[]);
+        assert_eq!(empty.bounding_box(), None);
+        assert_eq!(Ascii::default().render(&empty), "");
+        assert_eq!(Pbm.render(&empty), "P1\n0 0\n");
+
+        let mut out = Vec::new();
+        Braille.write(&sheet("##\n##\n##\n##\n"), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "⣿\n");
+    }
+
+    #[test]
+    fn fold_lines() {
+        for (line, operation) in [
+            ("fold along x=5", Operation::FoldX(5, Keep::Low)),
+            ("fold right along x=5", Operation::FoldX(5, Keep::High)),
+            ("fold up along y=7", Operation::FoldY(7, Keep::Low)),
+            ("fold down along y=7", Operation::FoldY(7, Keep::High)),
+            ("fold along y=x", Operation::FoldDiagonal(0, Keep::Low)),
+            ("fold down along y=x-3", Operation::FoldDiagonal(-3, Keep::High)),
+        ] {
+            assert_eq!(line.parse(), Ok(operation));
+            assert_eq!(operation.to_string().parse(), Ok(operation));
+        }
+
+        // The dot on the line disappears instead of surviving the fold.
+        let mut state = sheet("#.#.#\n");
+        state.execute_operation(Operation::FoldX(2, Keep::Low));
+        assert_eq!(Ascii::default().render(&state), "#\n");
+
+        let mut state = sheet("##...\n");
+        state.execute_operation(Operation::FoldX(2, Keep::High));
+        assert_eq!(state.bounding_box(), Some(BoundingBox { min: Point::new(3, 0), max: Point::new(4, 0) }));
+        assert_eq!(Ascii::default().render(&state), "##\n");
+
+        let mut state = sheet("#\n.\n#\n#\n");
+        state.execute_operation(Operation::FoldY(1, Keep::High));
+        assert_eq!(Ascii::default().render(&state), "#\n#\n");
+        assert_eq!(state.get_point_count(), 2);
+
+        // Folding along y = x + 1 reflects the dot below the line to the upper right and drops the one on it.
+        let mut state = State::from_points(&[Point::new(0, 3), Point::new(2, 2), Point::new(0, 1)]);
+        state.execute_operation(Operation::FoldDiagonal(1, Keep::Low));
+        assert_eq!(state.bounding_box(), Some(BoundingBox { min: Point::new(2, 1), max: Point::new(2, 2) }));
+        assert_eq!(state.get_point_count(), 2);
+
+        let mut state = State::from_points(&[Point::new(0, 3), Point::new(2, 2)]);
+        state.execute_operation(Operation::FoldDiagonal(1, Keep::High));
+        assert_eq!(state.bounding_box(), Some(BoundingBox { min: Point::new(0, 3), max: Point::new(1, 3) }));
+    }
+
+    #[test]
+    fn composed_folds() {
+        let mut lcg = Lcg::new(0x2545f491);
+        let mut random = |n: usize| lcg.below(n) as i32;
+        let points = (0..5000).map(|_| Point::new(random(400) - 50, random(300) - 50)).collect_vec();
+        let operations = (0..12)
+            .map(|_| {
+                let keep = if random(2) == 0 { Keep::Low } else { Keep::High };
+                match random(3) {
+                    0 => Operation::FoldX(random(300), keep),
+                    1 => Operation::FoldY(random(200), keep),
+                    _ => Operation::FoldDiagonal(random(100) - 50, keep),
+                }
+            })
+            .collect_vec();
+
+        let mut incremental = State::from_points(&points);
+        for &operation in &operations {
+            incremental.execute_operation(operation);
+        }
+        let mut composed = State::from_points(&points);
+        composed.execute_all(&operations);
+        assert_eq!(composed, incremental);
+        assert!(composed.get_point_count() > 100);
+
+        let transform = Transform::compose(&[Operation::FoldX(5, Keep::Low), Operation::FoldY(7, Keep::Low)]);
+        assert_eq!(transform.apply(Point::new(8, 10)), Some(Point::new(2, 4)));
+        assert_eq!(transform.apply(Point::new(8, 7)), None);
+        assert_eq!(transform.apply(Point::new(1, 1)), Some(Point::new(1, 1)));
+    }
+
+    #[test]
+    fn unfolding() {
+        let operations = [Operation::FoldX(2, Keep::Low), Operation::FoldY(1, Keep::Low)];
+        let transform = Transform::compose(&operations);
+        assert_eq!(
+            transform.preimages(Point::new(0, 0)),
+            vec![Point::new(0, 0), Point::new(4, 0), Point::new(0, 2), Point::new(4, 2)]
+        );
+        assert_eq!(transform.preimages(Point::new(2, 0)), vec![]);
+
+        let target = State::from_points(&[Point::new(0, 0), Point::new(1, 0)]);
+        let minimal = target.unfold(&operations).unwrap();
+        assert_eq!(minimal.get_point_count(), 2);
+
+        // Fifteen non-empty pre-image subsets for each of the two dots.
+        let sheets = target.unfoldings(&operations).unwrap().collect_vec();
+        assert_eq!(sheets.len(), 15 * 15);
+        for mut sheet in sheets {
+            sheet.execute_all(&operations);
+            assert_eq!(sheet, target);
+        }
+
+        let unreachable = State::from_points(&[Point::new(0, 0), Point::new(3, 0)]);
+        assert_eq!(unreachable.unfold(&operations), Err(Point::new(3, 0)));
+        assert!(unreachable.unfoldings(&operations).is_err());
+    }
+
+    #[test]
+    fn generator() {
+        assert_eq!(State::from_letters("HI"), Err('I'));
+        assert_eq!(generate_puzzle("HI", 4, 1), Err(GeneratorError::UnknownLetter('I')));
+        assert_eq!(generate_puzzle("AB", 55, 1), Err(GeneratorError::TooManyFolds));
+
+        // The deepest sequence that still fits is cheap, as only the sampled pre-images are built.
+        let (points, operations) = parse_input(&generate_puzzle("AB", 54, 3).unwrap()).unwrap();
+        let mut state = State::from_points(&points);
+        state.execute_all(&operations);
+        assert_eq!(state.read_letters(), Ok("AB".to_string()));
+
+        let puzzle = generate_puzzle("HELLO", 6, 7).unwrap();
+        let (points, operations) = parse_input(&puzzle).unwrap();
+        assert_eq!(operations.len(), 6);
+        assert_eq!(operations[5], Operation::FoldX(24, Keep::Low));
+        assert!(points.iter().all(|point| point.x() >= 0 && point.y() >= 0));
+
+        let mut state = State::from_points(&points);
+        state.execute_all(&operations);
+        assert_eq!(state.read_letters(), Ok("HELLO".to_string()));
+    }
+
+    #[test]
+    fn trace() {
+        let mut state = sheet(concat!(
+            "#...#\n",
+            "..#.#\n",
+            "#....\n",
+        ));
+        let operations = [Operation::FoldX(2, Keep::Low), Operation::FoldY(1, Keep::Low)];
+        let steps = state.trace(&operations, Some(&Ascii::default())).collect_vec();
+        assert_eq!(steps, vec![
+            FoldStep {
+                operation: Operation::FoldX(2, Keep::Low),
+                dots_before: 5,
+                dots_after: 3,
+                overlaps: 1,
+                bounding_box: Some(BoundingBox { min: Point::new(0, 0), max: Point::new(0, 2) }),
+                rendering: Some("#\n#\n#\n".to_string()),
+            },
+            FoldStep {
+                operation: Operation::FoldY(1, Keep::Low),
+                dots_before: 3,
+                dots_after: 1,
+                overlaps: 1,
+                bounding_box: Some(BoundingBox { min: Point::new(0, 0), max: Point::new(0, 0) }),
+                rendering: Some("#\n".to_string()),
+            },
+        ]);
+        assert_eq!(state.get_point_count(), 1);
+
+        let mut state = sheet("#.#\n");
+        let step = state.trace(&[Operation::FoldX(1, Keep::Low)], None).next().unwrap();
+        assert_eq!((step.dots_after, step.overlaps, step.rendering), (1, 1, None));
+    }
+
+    #[test]
+    fn input_sections() {
+        let (points, operations) = parse_input("6,10\r\n0,14 \r\n\r\n  \r\nfold along y=7\r\nfold along x=5\r\n\n").unwrap();
+        assert_eq!(points, vec![Point::new(6, 10), Point::new(0, 14)]);
+        assert_eq!(operations, vec![Operation::FoldY(7, Keep::Low), Operation::FoldX(5, Keep::Low)]);
+
+        assert_eq!(parse_input(""), Err(InputError::MissingDots));
+        assert_eq!("3,x".parse::<Point>(), Err("Malformed dot: 3,x".to_string()));
+        assert_eq!(parse_input("1,2\n3,4\n"), Err(InputError::MissingFolds));
+        assert_eq!(parse_input("\nfold along x=1\n"), Err(InputError::MissingDots));
+        assert_eq!(
+            parse_input("1,2\n3;4\n\nfold along x=1\n"),
+            Err(InputError::MalformedDot { line: 2, text: "3;4".to_string() })
+        );
+        assert_eq!(
+            parse_input("1,2\n\nfold along x=1\nfold along z=1\n"),
+            Err(InputError::MalformedFold { line: 4, text: "fold along z=1".to_string() })
+        );
+        assert_eq!(
+            parse_input("1,2\n\nfold along x=1\n\n\n5,5\n"),
+            Err(InputError::ExtraSection { line: 6 })
+        );
+        assert_eq!(
+            InputError::MalformedFold { line: 4, text: "fold".to_string() }.to_string(),
+            "line 4: unknown fold instruction `fold`"
+        );
+    }
+
+    #[test]
+    fn generated_input_parses() {
+        assert_eq!(generate_puzzle("", 4, 1), Err(GeneratorError::EmptyMessage));
+        assert_eq!(generate_puzzle("A", 0, 1), Err(GeneratorError::NoFolds));
+        for (message, folds) in [("A", 1), ("HELLO", 2), ("ZEBRA", 5), ("CJKPU", 12)] {
+            let puzzle = generate_puzzle(message, folds, 11).unwrap();
+            let (points, operations) = parse_input(&puzzle).unwrap();
+            assert_eq!(operations.len(), folds);
+            let mut state = State::from_points(&points);
+            state.execute_all(&operations);
+            assert_eq!(state.read_letters(), Ok(message.to_string()));
+        }
+    }
+
+    #[test]
+    fn fold_overflow() {
+        let (points, operations) = parse_input("2100000000,0\n5,-2147483648\n1,1\n\nfold along x=2000000000\nfold along y=x+2147483647\n").unwrap();
+        let mut state = State::from_points(&points);
+        state.execute_operation(operations[0]);
+        assert_eq!(state.get_point_count(), 3);
+        assert!(state.contains(1900000000, 0));
+
+        // Mirroring (5, i32::MIN) across y = x + i32::MAX would put x below i32::MIN.
+        let mut incremental = State::from_points(&points);
+        for step in incremental.trace(&operations, None) {
+            assert!(step.dots_after <= step.dots_before);
+        }
+        let mut composed = State::from_points(&points);
+        composed.execute_all(&operations);
+        assert_eq!(composed, incremental);
+
+        let transform = Transform::compose(&[Operation::FoldX(2000000000, Keep::Low)]);
+        assert_eq!(transform.preimages(Point::new(-2000000000, 0)), vec![Point::new(-2000000000, 0)]);
+        let samples = (0..8)
+            .map(|seed| transform.sample_preimage(Point::new(-2000000000, 0), &mut Lcg::new(seed)))
+            .collect_vec();
+        assert!(samples.contains(&None));
+        assert!(samples.iter().flatten().all(|&point| point == Point::new(-2000000000, 0)));
+        let transform = Transform::compose(&[Operation::FoldX(2000000000, Keep::High)]);
+        assert_eq!(transform.apply(Point::new(-200000000, 0)), None);
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::slice::Iter;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct Grid<T> {
+    width: usize,
+    height: usize,
+    boundary: Boundary,
+    cells: Vec<T>,
+}
+
+impl<T> Grid<T> {
+    pub fn new(width: usize, height: usize, cells: Vec<T>) -> Grid<T> {
+        assert_eq!(width * height, cells.len(), "grid size does not match cell count");
+        Grid { width, height, boundary: Boundary::Open, cells }
+    }
+
+    pub fn from_rows(rows: Vec<Vec<T>>) -> Grid<T> {
+        let height = rows.len();
+        let width = rows.first().map(|row| row.len()).unwrap_or(0);
+        assert!(rows.iter().all(|row| row.len() == width), "grid rows differ in length");
+        Grid::new(width, height, rows.into_iter().flatten().collect())
+    }
+
+    pub fn with_boundary(mut self, boundary: Boundary) -> Grid<T> {
+        self.boundary = boundary;
+        self
+    }
+
+    pub fn boundary(&self) -> Boundary {
+        self.boundary
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<&T> {
+        self.index(x, y).map(|i| &self.cells[i])
+    }
+
+    pub fn get_mut(&mut self, x: i32, y: i32) -> Option<&mut T> {
+        self.index(x, y).map(move |i| &mut self.cells[i])
+    }
+
+    pub fn cells(&self) -> &[T] {
+        &self.cells
+    }
+
+    pub fn cells_mut(&mut self) -> &mut [T] {
+        &mut self.cells
+    }
+
+    pub fn neighbours(&self, neighbourhood: Neighbourhood, index: usize) -> Neighbours<'_, T> {
+        Neighbours { grid: self, index, offsets: neighbourhood.offsets().iter() }
+    }
+
+    pub fn render<F>(&self, f: F) -> String
+        where F: Fn(&T) -> char {
+        self.cells.chunks(self.width.max(1))
+            .map(|row| row.iter().map(&f).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            None
+        } else {
+            Some(y as usize * self.width + x as usize)
+        }
+    }
+
+    fn neighbour_indices(&self, neighbourhood: Neighbourhood, index: usize) -> impl Iterator<Item = usize> {
+        let (width, height, boundary) = (self.width, self.height, self.boundary);
+        neighbourhood.offsets().iter()
+            .filter_map(move |&offset| boundary.neighbour(width, height, index, offset))
+    }
+}
+
+pub struct Neighbours<'a, T> {
+    grid: &'a Grid<T>,
+    index: usize,
+    offsets: Iter<'static, (i32, i32)>,
+}
+
+impl<'a, T> Iterator for Neighbours<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let grid = self.grid;
+        self.offsets.by_ref()
+            .find_map(|&offset| grid.boundary.neighbour(grid.width, grid.height, self.index, offset))
+            .map(|i| &grid.cells[i])
+    }
+}
+
+// Open boundaries drop neighbours outside the grid, toroidal ones wrap around to the opposite
+// edge and reflecting ones mirror them back inside, so cells next to an edge see some of their
+// neighbours twice. A cell is never its own neighbour, even on grids narrower than the
+// neighbourhood.
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Boundary {
+    Open,
+    Toroidal,
+    Reflecting,
+}
+
+impl Boundary {
+    fn neighbour(self, width: usize, height: usize, index: usize, (dx, dy): (i32, i32)) -> Option<usize> {
+        let (width, height) = (width as i32, height as i32);
+        let x = self.resolve(index as i32 % width + dx, width)?;
+        let y = self.resolve(index as i32 / width + dy, height)?;
+        let neighbour = (y * width + x) as usize;
+        if neighbour == index { None } else { Some(neighbour) }
+    }
+
+    fn resolve(self, v: i32, size: i32) -> Option<i32> {
+        match self {
+            _ if (0..size).contains(&v) => Some(v),
+            Self::Open => None,
+            Self::Toroidal => Some(v.rem_euclid(size)),
+            Self::Reflecting if v < 0 => Some((-v - 1).min(size - 1)),
+            Self::Reflecting => Some((2 * size - v - 1).max(0)),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Neighbourhood {
+    Moore,
+    VonNeumann,
+}
+
+impl Neighbourhood {
+    fn offsets(&self) -> &'static [(i32, i32)] {
+        match self {
+            Self::Moore => &[(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)],
+            Self::VonNeumann => &[(1, 0), (0, 1), (-1, 0), (0, -1)],
+        }
+    }
+}
+
+// A step runs three phases: a synchronous update of every cell from the previous generation,
+// a cascade in which triggered cells fire and propagate to their neighbours until nothing
+// triggers any more, and a settle pass whose events are counted as the result of the step.
+pub trait Rule {
+    type Cell: Clone;
+
+    fn neighbourhood(&self) -> Neighbourhood {
+        Neighbourhood::Moore
+    }
+
+    fn update(&self, cell: &Self::Cell, neighbours: Neighbours<'_, Self::Cell>) -> Self::Cell;
+
+    fn triggers(&self, _cell: &Self::Cell) -> bool {
+        false
+    }
+
+    fn fire(&self, _cell: &mut Self::Cell) {}
+
+    fn propagate(&self, _neighbour: &mut Self::Cell) {}
+
+    fn settle(&self, _cell: &mut Self::Cell) -> bool {
+        false
+    }
+}
+
+pub struct Automaton<R: Rule> {
+    rule: R,
+    grid: Grid<R::Cell>,
+    generation: usize,
+    history: Option<Vec<Grid<R::Cell>>>,
+    last_cascade: Vec<Vec<usize>>,
+}
+
+impl<R: Rule> Automaton<R> {
+    pub fn new(rule: R, grid: Grid<R::Cell>) -> Automaton<R> {
+        Automaton { rule, grid, generation: 0, history: None, last_cascade: Vec::new() }
+    }
+
+    pub fn with_generation(mut self, generation: usize) -> Automaton<R> {
+        self.generation = generation;
+        self
+    }
+
+    pub fn with_history(mut self) -> Automaton<R> {
+        self.history = Some(vec![self.grid.clone()]);
+        self
+    }
+
+    pub fn rule(&self) -> &R {
+        &self.rule
+    }
+
+    pub fn grid(&self) -> &Grid<R::Cell> {
+        &self.grid
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn history(&self) -> Option<&[Grid<R::Cell>]> {
+        self.history.as_deref()
+    }
+
+    // Cell indices fired in each cascade wave of the most recent step.
+    pub fn last_cascade(&self) -> &[Vec<usize>] {
+        &self.last_cascade
+    }
+
+    pub fn step(&mut self) -> usize {
+        self.grid.update_all(&self.rule);
+        self.last_cascade = self.grid.cascade(&self.rule);
+        let events = self.grid.settle_all(&self.rule);
+        self.finish_step(events)
+    }
+
+    // Reference implementation of the cascade which rescans the whole grid each round,
+    // kept to check and benchmark the worklist version against.
+    #[cfg(test)]
+    pub fn step_rescan(&mut self) -> usize {
+        self.last_cascade.clear();
+        let events = self.grid.advance_rescan(&self.rule);
+        self.finish_step(events)
+    }
+
+    pub fn run(&mut self, steps: usize) -> usize {
+        (0..steps).map(|_| self.step()).sum()
+    }
+
+    pub fn render<F>(&self, f: F) -> String
+        where F: Fn(&R::Cell) -> char {
+        self.grid.render(f)
+    }
+
+    fn finish_step(&mut self, events: usize) -> usize {
+        self.generation += 1;
+        if let Some(history) = self.history.as_mut() {
+            history.push(self.grid.clone());
+        }
+        events
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Cycle {
+    pub start: usize,
+    pub length: usize,
+}
+
+impl<R: Rule> Automaton<R> where R::Cell: Eq + Hash {
+    // Brent's algorithm over the states reachable from the current grid, with `start` counted
+    // from the current generation. Gives up once the search has taken `max_steps` steps.
+    pub fn find_cycle(&self, max_steps: usize) -> Option<Cycle> {
+        let mut power = 1;
+        let mut length = 1;
+        let mut tortoise = self.grid.clone();
+        let mut tortoise_hash = fingerprint(&tortoise);
+        let mut hare = self.grid.clone();
+        hare.advance(&self.rule);
+        let mut steps = 1;
+        while fingerprint(&hare) != tortoise_hash || hare != tortoise {
+            if steps >= max_steps {
+                return None;
+            }
+            if power == length {
+                tortoise = hare.clone();
+                tortoise_hash = fingerprint(&tortoise);
+                power *= 2;
+                length = 0;
+            }
+            hare.advance(&self.rule);
+            length += 1;
+            steps += 1;
+        }
+
+        let mut tortoise = self.grid.clone();
+        let mut hare = self.grid.clone();
+        for _ in 0..length {
+            hare.advance(&self.rule);
+        }
+        let mut start = 0;
+        while hare != tortoise {
+            tortoise.advance(&self.rule);
+            hare.advance(&self.rule);
+            start += 1;
+        }
+        Some(Cycle { start, length })
+    }
+
+    // Runs `steps` steps, skipping whole periods once a cycle is known. Only the simulated
+    // steps are recorded in the history, but the generation counter advances by `steps`.
+    pub fn fast_forward(&mut self, steps: usize, max_search: usize) -> Option<usize> {
+        if steps <= max_search {
+            return Some(self.run(steps));
+        }
+
+        let cycle = self.find_cycle(max_search)?;
+        let generation = self.generation;
+        let remaining = steps - cycle.start;
+        let mut events = self.run(cycle.start);
+        events += self.run(cycle.length) * (remaining / cycle.length);
+        events += self.run(remaining % cycle.length);
+        self.generation = generation + steps;
+        Some(events)
+    }
+}
+
+fn fingerprint<T: Hash>(grid: &Grid<T>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    grid.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<T: Clone> Grid<T> {
+    fn advance<R: Rule<Cell = T>>(&mut self, rule: &R) -> usize {
+        self.update_all(rule);
+        self.cascade(rule);
+        self.settle_all(rule)
+    }
+
+    #[cfg(test)]
+    fn advance_rescan<R: Rule<Cell = T>>(&mut self, rule: &R) -> usize {
+        self.update_all(rule);
+        while self.cascade_rescan(rule) {}
+        self.settle_all(rule)
+    }
+
+    fn update_all<R: Rule<Cell = T>>(&mut self, rule: &R) {
+        let neighbourhood = rule.neighbourhood();
+        let cells = (0..self.len())
+            .map(|i| rule.update(&self.cells[i], self.neighbours(neighbourhood, i)))
+            .collect();
+        self.cells = cells;
+    }
+
+    // Cells are fired wave by wave: the first wave holds the cells triggered by the update and
+    // each following one the cells triggered while firing the previous wave. A cell already
+    // waiting in a wave is not queued again, so a step costs one pass over the grid plus the
+    // work done by the cells that actually fire. Returns the indices fired per wave.
+    fn cascade<R: Rule<Cell = T>>(&mut self, rule: &R) -> Vec<Vec<usize>> {
+        let neighbourhood = rule.neighbourhood();
+        let mut waves = Vec::new();
+        let mut wave: Vec<usize> = (0..self.len())
+            .filter(|&i| rule.triggers(&self.cells[i]))
+            .collect();
+        let mut queued = vec![false; self.len()];
+        for &i in &wave {
+            queued[i] = true;
+        }
+
+        while !wave.is_empty() {
+            let mut fired = Vec::new();
+            let mut next = Vec::new();
+            for i in wave {
+                queued[i] = false;
+                if !rule.triggers(&self.cells[i]) {
+                    continue;
+                }
+                rule.fire(&mut self.cells[i]);
+                fired.push(i);
+                for n in self.neighbour_indices(neighbourhood, i) {
+                    rule.propagate(&mut self.cells[n]);
+                    if !queued[n] && rule.triggers(&self.cells[n]) {
+                        queued[n] = true;
+                        next.push(n);
+                    }
+                }
+            }
+            waves.push(fired);
+            wave = next;
+        }
+        waves
+    }
+
+    #[cfg(test)]
+    fn cascade_rescan<R: Rule<Cell = T>>(&mut self, rule: &R) -> bool {
+        let firing: Vec<usize> = (0..self.len())
+            .filter(|&i| rule.triggers(&self.cells[i]))
+            .collect();
+
+        for &i in &firing {
+            rule.fire(&mut self.cells[i]);
+        }
+
+        let neighbourhood = rule.neighbourhood();
+        let neighbours: Vec<usize> = firing.iter()
+            .flat_map(|&i| self.neighbour_indices(neighbourhood, i))
+            .collect();
+        for i in neighbours {
+            rule.propagate(&mut self.cells[i]);
+        }
+
+        !firing.is_empty()
+    }
+
+    fn settle_all<R: Rule<Cell = T>>(&mut self, rule: &R) -> usize {
+        let mut count = 0;
+        for cell in self.cells.iter_mut() {
+            if rule.settle(cell) { count += 1 };
+        }
+        count
+    }
+}
+
+pub struct Life;
+
+impl Rule for Life {
+    type Cell = bool;
+
+    fn update(&self, &alive: &bool, neighbours: Neighbours<'_, bool>) -> bool {
+        let alive_neighbours = neighbours.filter(|&&cell| cell).count();
+        matches!((alive, alive_neighbours), (true, 2) | (_, 3))
+    }
+
+    fn settle(&self, alive: &mut bool) -> bool {
+        *alive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Automaton, Boundary, Cycle, Grid, Life};
+
+    fn life_grid(s: &str) -> Grid<bool> {
+        Grid::from_rows(s.split_whitespace()
+            .map(|row| row.chars().map(|ch| ch == '#').collect())
+            .collect())
+    }
+
+    #[test]
+    fn blinker() {
+        let mut automaton = Automaton::new(Life, life_grid(".... ###. ....")).with_history();
+        assert_eq!(automaton.step(), 3);
+        assert_eq!(automaton.render(|&cell| if cell { '#' } else { '.' }), ".#..\n.#..\n.#..");
+        automaton.step();
+        assert_eq!(automaton.generation(), 2);
+
+        let history = automaton.history().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0], history[2]);
+        assert_ne!(history[0], history[1]);
+    }
+
+    #[test]
+    fn cycle() {
+        let glider = "\
+            .#...... ..#..... ###..... ........ ........ ........ ........ ........";
+        let automaton = Automaton::new(Life, life_grid(glider));
+        assert_eq!(automaton.find_cycle(20), None);
+        assert_eq!(automaton.find_cycle(1000), Some(Cycle { start: 23, length: 1 }));
+
+        let mut automaton = Automaton::new(Life, life_grid(".... ###. ....  ...."));
+        assert_eq!(automaton.find_cycle(1000), Some(Cycle { start: 0, length: 2 }));
+        assert_eq!(automaton.fast_forward(1_000_000_000_001, 1000), Some(3_000_000_000_003));
+        assert_eq!(automaton.generation(), 1_000_000_000_001);
+        assert_eq!(automaton.render(|&cell| if cell { '#' } else { '.' }), ".#..\n.#..\n.#..\n....");
+    }
+
+    #[test]
+    fn neighbours() {
+        let grid = Grid::new(3, 2, (0..6).collect());
+        assert_eq!(grid.neighbours(super::Neighbourhood::Moore, 0).sum::<i32>(), 1 + 3 + 4);
+        assert_eq!(grid.neighbours(super::Neighbourhood::VonNeumann, 4).sum::<i32>(), 1 + 3 + 5);
+        assert_eq!(grid.get(2, 1), Some(&5));
+        assert_eq!(grid.get(3, 0), None);
+
+        let grid = grid.with_boundary(Boundary::Toroidal);
+        assert_eq!(grid.neighbours(super::Neighbourhood::Moore, 0).sum::<i32>(), 2 * (1 + 2 + 4 + 5) + 3);
+        let grid = grid.with_boundary(Boundary::Reflecting);
+        assert_eq!(grid.neighbours(super::Neighbourhood::Moore, 0).sum::<i32>(), 2 * (1 + 3) + 4);
+    }
+}
@@ -0,0 +1,28 @@
+use std::env;
+use std::process::exit;
+use rust_aoc_2021::aoc::day13::generate_puzzle;
+
+const USAGE: &str = "usage: fold_message MESSAGE [FOLDS] [SEED]";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+// Prints a day 13 style puzzle input whose dots fold into the given message.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 3 {
+        usage();
+    }
+    let folds = args.get(1).map_or(Some(12), |n| n.parse().ok()).unwrap_or_else(|| usage());
+    let seed = args.get(2).map_or(Some(1), |n| n.parse().ok()).unwrap_or_else(|| usage());
+
+    match generate_puzzle(&args[0].to_uppercase(), folds, seed) {
+        Ok(puzzle) => print!("{}", puzzle),
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(1);
+        }
+    }
+}
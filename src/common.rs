@@ -10,4 +10,41 @@ pub fn read_to_string(file_name: &str) -> String {
     let mut string = String::new();
     BufReader::new(file).read_to_string(&mut string).unwrap();
     string
-}
\ No newline at end of file
+}
+
+// Splits text into blocks separated by blank lines, pairing each line with its 1-based line
+// number. CRLF endings and trailing whitespace are stripped, and runs of blank or whitespace-only
+// lines count as a single separator.
+pub fn split_sections(text: &str) -> Vec<Vec<(usize, &str)>> {
+    let mut sections = Vec::new();
+    let mut section = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            if !section.is_empty() {
+                sections.push(std::mem::take(&mut section));
+            }
+        } else {
+            section.push((i + 1, line));
+        }
+    }
+    if !section.is_empty() {
+        sections.push(section);
+    }
+    sections
+}
+
+// Deterministic linear congruential generator for test data and generated inputs.
+pub struct Lcg(u64);
+
+impl Lcg {
+    pub fn new(seed: u64) -> Lcg {
+        Lcg(seed)
+    }
+
+    // Uniform enough in 0..n for n far below 2^31.
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) as usize % n
+    }
+}
@@ -1,7 +1,8 @@
 #[allow(dead_code)]
 
 mod common;
-mod aoc;
+mod automaton;
+pub mod aoc;
 
 #[cfg(test)]
 mod tests {